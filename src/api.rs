@@ -1,13 +1,21 @@
+mod auth;
 mod catchall;
+mod http_clients;
 mod models;
 mod open_ai;
+mod rate_limits;
 mod result;
 mod state;
+mod usage;
 
 use anyhow::Result;
 use axum::Router;
 use std::{net::SocketAddr, path::PathBuf};
-use tokio::{net::TcpListener, runtime::Runtime};
+use tokio::{
+    net::TcpListener,
+    runtime::Runtime,
+    signal::unix::{SignalKind, signal},
+};
 
 pub fn serve_sync(address: &SocketAddr, config_path: PathBuf) -> Result<()> {
     Runtime::new()?.block_on(async { serve(address, config_path).await })
@@ -17,16 +25,42 @@ pub async fn serve(address: &SocketAddr, config_path: PathBuf) -> Result<()> {
     let state = state::ApiState::init(config_path).await?;
 
     let api = Router::new()
-        .nest("/herder", models::router())
+        .nest("/herder", models::router(state.clone()))
         .nest("/v1", open_ai::router())
         .route("/{*path}", catchall::handler())
-        .with_state(state);
+        .with_state(state.clone());
 
     let listener = TcpListener::bind(address).await?;
 
     println!("herder listening on {address}");
 
-    axum::serve(listener, api).await?;
+    state.spawn_default_loads();
+
+    axum::serve(
+        listener,
+        api.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
+
+    state.shutdown().await;
 
     Ok(())
 }
+
+/// Resolves on Ctrl+C or SIGTERM, which `hrdr stop` sends.
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
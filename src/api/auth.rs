@@ -0,0 +1,190 @@
+use axum::http::{HeaderMap, header::AUTHORIZATION};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+use subtle::{Choice, ConstantTimeEq};
+
+use crate::{
+    api::result::{ApiError, ApiResult},
+    config::{ClientConfig, Config},
+};
+
+/// Clients allowed to use the router, refreshed whenever the config is (re)loaded
+#[derive(Clone, Default)]
+pub struct ClientAuth {
+    clients: Arc<RwLock<HashMap<String, ClientConfig>>>,
+}
+
+/// Who a request was authenticated as
+#[derive(Debug, Clone)]
+pub enum Caller {
+    /// No `clients` are configured, so the router is open to anyone
+    Anyone,
+    Client {
+        name: String,
+        config: ClientConfig,
+    },
+}
+
+impl ClientAuth {
+    pub fn set_clients(&self, config: &Config) {
+        *self.clients.write().unwrap() = config.clients.clone().unwrap_or_default();
+    }
+
+    /// Identifies the caller by its bearer key, before it is replaced with the upstream key.
+    pub fn authenticate(&self, headers: &HeaderMap, ip: IpAddr) -> ApiResult<Caller> {
+        let clients = self.clients.read().unwrap();
+
+        if clients.is_empty() {
+            return Ok(Caller::Anyone);
+        }
+
+        let key = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, key)| key.trim())
+            .ok_or(ApiError::Unauthorized("Missing bearer key".to_string()))?;
+
+        // Every client's key is compared as a fixed length digest in constant time, so neither the
+        // key nor its length can be guessed from response timings
+        let key = digest(key);
+        let mut matched = None;
+
+        for (name, client) in clients.iter() {
+            let matches = client.key.secret().map_or(Choice::from(0), |secret| {
+                digest(secret.expose_ref()).ct_eq(&key)
+            });
+
+            if bool::from(matches) {
+                matched.get_or_insert((name, client));
+            }
+        }
+
+        let (name, config) =
+            matched.ok_or(ApiError::Unauthorized("Invalid bearer key".to_string()))?;
+
+        if !config.allows_ip(ip) {
+            return Err(ApiError::Forbidden(format!(
+                "Client '{name}' may not connect from {ip}"
+            )));
+        }
+
+        Ok(Caller::Client {
+            name: name.clone(),
+            config: config.clone(),
+        })
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    ring::digest::digest(&ring::digest::SHA256, key.as_bytes())
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes")
+}
+
+impl Caller {
+    /// Name of the client, `None` when the router is open to anyone
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Caller::Anyone => None,
+            Caller::Client { name, .. } => Some(name),
+        }
+    }
+
+    pub fn allows_model(&self, alias: &str) -> bool {
+        match self {
+            Caller::Anyone => true,
+            Caller::Client { config, .. } => config.allows_model(alias),
+        }
+    }
+
+    pub fn authorize_admin(&self) -> ApiResult<()> {
+        match self {
+            Caller::Client { name, config } if !config.is_admin() => Err(ApiError::Forbidden(
+                format!("Client '{name}' is not an admin"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn authorize_model(&self, alias: &str) -> ApiResult<()> {
+        match self {
+            Caller::Client { name, config } if !config.allows_model(alias) => Err(
+                ApiError::Forbidden(format!("Client '{name}' may not use model '{alias}'")),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    use super::*;
+
+    fn client_auth() -> ClientAuth {
+        let client_auth = ClientAuth::default();
+
+        *client_auth.clients.write().unwrap() = serde_json::from_value(json!({
+            "alice": { "key": "alice-key", "models": ["*"] },
+            "bob": { "key": "bob-key", "models": ["*"], "ips": ["10.0.0.0/8"] }
+        }))
+        .unwrap();
+
+        client_auth
+    }
+
+    fn authenticate(authorization: &str, ip: &str) -> ApiResult<Caller> {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+
+        client_auth().authenticate(&headers, ip.parse().unwrap())
+    }
+
+    #[test]
+    fn clients_are_identified_by_their_key() {
+        let caller = authenticate("Bearer bob-key", "10.0.0.1").unwrap();
+
+        assert_eq!(caller.name(), Some("bob"));
+    }
+
+    #[test]
+    fn the_bearer_scheme_is_case_insensitive() {
+        let caller = authenticate("bearer alice-key", "127.0.0.1").unwrap();
+
+        assert_eq!(caller.name(), Some("alice"));
+    }
+
+    #[test]
+    fn unknown_keys_and_other_schemes_are_unauthorized() {
+        for authorization in [
+            "Bearer alice",
+            "Bearer alice-key2",
+            "Basic alice-key",
+            "alice-key",
+        ] {
+            assert!(
+                matches!(
+                    authenticate(authorization, "127.0.0.1"),
+                    Err(ApiError::Unauthorized(_))
+                ),
+                "{authorization}"
+            );
+        }
+    }
+
+    #[test]
+    fn clients_outside_their_ip_ranges_are_forbidden() {
+        assert!(matches!(
+            authenticate("Bearer bob-key", "192.168.0.1"),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+}
@@ -1,43 +1,66 @@
 use anyhow::Context;
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{ConnectInfo, Request, State},
     http::Response,
     routing::{MethodRouter, any},
 };
+use chrono::Utc;
 use http::{
-    HeaderMap, Method, Uri, Version,
     header::{CONTENT_LENGTH, CONTENT_TYPE, HOST},
     request::Parts,
 };
 use reqwest::header::AUTHORIZATION;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use std::mem::take;
+use std::{mem::take, net::SocketAddr};
+use tokio_stream::StreamExt;
+use utils_rs::option::as_bool::AsBool;
 
 use crate::{
     api::{
+        auth::Caller,
+        rate_limits::{AcquiredLimits, RateLimitScope},
         result::{ApiError, ApiResult},
         state::ApiState,
+        usage::UsageTracker,
     },
     config::ModelConfig,
+    models::{ModelState, RequestGuard},
+    usage::UsageRecord,
 };
 
+/// Attempts to load a model and take a slot on it, for when it's unloaded in between
+const BEGIN_REQUEST_ATTEMPTS: usize = 2;
+
 pub fn handler() -> MethodRouter<ApiState> {
     any(route_request)
 }
 
 #[axum::debug_handler]
-async fn route_request(State(state): State<ApiState>, request: Request) -> ApiResult {
+async fn route_request(
+    State(state): State<ApiState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    mut request: Request,
+) -> ApiResult {
+    let caller = state
+        .client_auth()
+        .authenticate(request.headers(), address.ip())?;
+
+    // The router's key is not meant for upstreams, which get their own `api-key` if any
+    if let Caller::Client { .. } = caller {
+        request.headers_mut().remove(AUTHORIZATION);
+    }
+
     if request
         .headers()
         .get(CONTENT_TYPE)
         .and_then(|x| x.to_str().ok())
         .is_some_and(|x| x.to_lowercase().contains("json"))
     {
-        route_request_by_json_model_field_or_model_header(state, request).await
+        route_request_by_json_model_field_or_model_header(state, caller, request).await
     } else {
-        route_request_by_model_header(state, request).await
+        route_request_by_model_header(state, caller, request).await
     }
 }
 
@@ -57,18 +80,10 @@ enum JsonBody {
 
 async fn route_request_by_json_model_field_or_model_header(
     state: ApiState,
+    caller: Caller,
     request: Request,
 ) -> ApiResult {
-    let (
-        Parts {
-            method,
-            uri,
-            mut headers,
-            version,
-            ..
-        },
-        body,
-    ) = request.into_parts();
+    let (mut parts, body) = request.into_parts();
 
     let body_bytes = axum::body::to_bytes(body, usize::MAX)
         .await
@@ -84,20 +99,27 @@ async fn route_request_by_json_model_field_or_model_header(
     };
 
     let alias = alias_from_json_body
-        .or_else(|| headers.get("model").and_then(|v| v.to_str().ok()))
+        .or_else(|| parts.headers.get("model").and_then(|v| v.to_str().ok()))
         .ok_or(ApiError::BadRequest(
             "No model specified in body nor headers".into(),
         ))?;
 
-    let model_config = state
-        .models()
-        .get_loaded_config(alias)
-        .await
-        .ok_or(ApiError::NotFound(format!("Model alias {alias} not found")))?;
+    caller.authorize_model(alias)?;
+
+    let limits = acquire_limits(&state, &caller, alias)?;
+    let (model_config, guard) = begin_model_request(&state, alias).await?;
+
+    let mut strip_stream_usage = false;
 
     let json_body = if let JsonBody::Object(mut object) = json_body {
         object.model = Some(model_config.id().to_string());
 
+        if parts.uri.path().ends_with("/completions")
+            && object.additional_properties.get("stream") == Some(&Value::Bool(true))
+        {
+            strip_stream_usage = !include_stream_usage(&mut object);
+        }
+
         JsonBody::Object(object)
     } else {
         json_body
@@ -106,7 +128,7 @@ async fn route_request_by_json_model_field_or_model_header(
     let body =
         serde_json::to_vec(&json_body).context("Failed to serialize json body into bytes")?;
 
-    headers.insert(
+    parts.headers.insert(
         CONTENT_LENGTH,
         body.len()
             .to_string()
@@ -116,56 +138,166 @@ async fn route_request_by_json_model_field_or_model_header(
 
     let body = reqwest::Body::from(body);
 
-    route_request_parts_and_body_by_model_config(model_config, method, uri, headers, version, body)
-        .await
+    route_request_parts_and_body_by_model_config(
+        &state,
+        &caller,
+        limits,
+        (model_config, guard),
+        parts,
+        body,
+        strip_stream_usage,
+    )
+    .await
 }
 
-async fn route_request_by_model_header(state: ApiState, request: Request) -> ApiResult {
+/// Streams only report `usage` when asked to. It is forced on so token quotas can't be dodged by
+/// opting out. Returns whether the client asked for it, otherwise the extra final chunk is
+/// stripped from the response.
+fn include_stream_usage(object: &mut JsonObjectBody) -> bool {
+    let options = object
+        .additional_properties
+        .entry("stream_options")
+        .or_insert(Value::Null);
+
+    if !options.is_object() {
+        *options = Value::Object(Map::new());
+    }
+
+    let Some(options) = options.as_object_mut() else {
+        return false;
+    };
+
+    let asked = options.get("include_usage") == Some(&Value::Bool(true));
+
+    options.insert("include_usage".to_string(), Value::Bool(true));
+
+    asked
+}
+
+async fn route_request_by_model_header(
+    state: ApiState,
+    caller: Caller,
+    request: Request,
+) -> ApiResult {
     let alias = request
         .headers()
         .get("model")
         .and_then(|v| v.to_str().ok())
         .ok_or(ApiError::BadRequest("No model specified in headers".into()))?;
 
-    let model_config = state
-        .models()
-        .get_loaded_config(alias)
-        .await
-        .ok_or(ApiError::NotFound(format!("Model alias {alias} not found")))?;
-
-    let (
-        Parts {
-            method,
-            uri,
-            headers,
-            version,
-            ..
-        },
-        body,
-    ) = request.into_parts();
+    caller.authorize_model(alias)?;
+
+    let limits = acquire_limits(&state, &caller, alias)?;
+    let (model_config, guard) = begin_model_request(&state, alias).await?;
+
+    let (parts, body) = request.into_parts();
 
     let body = reqwest::Body::wrap_stream(body.into_data_stream());
 
-    route_request_parts_and_body_by_model_config(model_config, method, uri, headers, version, body)
-        .await
+    route_request_parts_and_body_by_model_config(
+        &state,
+        &caller,
+        limits,
+        (model_config, guard),
+        parts,
+        body,
+        false,
+    )
+    .await
+}
+
+/// Counts the request against the caller's and the alias' limits together. Done before a model is
+/// loaded for it, so an exhausted client or alias can't trigger spawns and evictions.
+fn acquire_limits(state: &ApiState, caller: &Caller, alias: &str) -> ApiResult<AcquiredLimits> {
+    let config = state.config();
+
+    let scopes = RateLimitScope::for_client(caller)
+        .into_iter()
+        .chain(
+            config
+                .get_on_demand_model_index(alias)
+                .ok()
+                .and_then(|index| RateLimitScope::for_alias(&config.models[index])),
+        )
+        .collect();
+
+    AcquiredLimits::acquire(state.rate_limits(), scopes).map_err(ApiError::RateLimited)
+}
+
+/// Loads the model if needed and takes a concurrency slot on it, trying again if the model was
+/// unloaded or restarted in between.
+async fn begin_model_request(
+    state: &ApiState,
+    alias: &str,
+) -> ApiResult<(ModelConfig, RequestGuard)> {
+    for _ in 0..BEGIN_REQUEST_ATTEMPTS {
+        ensure_started(state, alias).await?;
+
+        if let Some(started) = state
+            .models()
+            .begin_request(alias)
+            .await
+            .map_err(|err| ApiError::TooManyRequests(err.to_string()))?
+        {
+            return Ok(started);
+        }
+    }
+
+    Err(ApiError::Unavailable(format!(
+        "{alias} was unloaded before the request could be sent"
+    )))
+}
+
+/// Waits for the model to start, loading it on demand if it isn't loaded or has failed.
+async fn ensure_started(state: &ApiState, alias: &str) -> ApiResult<()> {
+    let config = state.config();
+    let on_demand =
+        config.load_on_demand.as_bool() && config.models.iter().any(|m| m.alias() == alias);
+
+    match state.models().wait_until_started(alias).await {
+        Some((_, ModelState::Ready)) => return Ok(()),
+        Some((_, ModelState::Failed { reason })) if !on_demand => {
+            return Err(ApiError::Unavailable(format!("{alias} failed: {reason}")));
+        }
+        Some((_, ModelState::Starting | ModelState::Failed { .. })) | None => {}
+    }
+
+    if !on_demand {
+        return Err(ApiError::NotFound(alias.to_string()));
+    }
+
+    state.models().load_on_demand(&config, alias).await?;
+
+    Ok(())
 }
 
 async fn route_request_parts_and_body_by_model_config(
-    model_config: ModelConfig,
-    method: Method,
-    uri: Uri,
-    mut headers: HeaderMap,
-    version: Version,
+    state: &ApiState,
+    caller: &Caller,
+    limits: AcquiredLimits,
+    (model_config, guard): (ModelConfig, RequestGuard),
+    parts: Parts,
     body: reqwest::Body,
+    strip_stream_usage: bool,
 ) -> ApiResult {
+    let Parts {
+        method,
+        uri,
+        mut headers,
+        version,
+        ..
+    } = parts;
+
     let path_and_query = uri
         .path_and_query()
         .context("Failed to extract path and query")?
         .as_str();
 
-    let url = model_config
+    let base_url = model_config
         .url()
-        .and_then(|url| url.join(path_and_query))
+        .context("Failed constructing the model url")?;
+    let url = base_url
+        .join(path_and_query)
         .context("Failed constructing the model url")?;
 
     let mut request = reqwest::Request::new(method, url);
@@ -185,15 +317,61 @@ async fn route_request_parts_and_body_by_model_config(
         );
     };
 
-    let mut response = reqwest::Client::new()
-        .execute(request)
-        .await
-        .context("Error passing on request")?;
+    let (client, settings) = state.http_clients().get_for(&model_config)?;
+    let response = client.execute(request);
+
+    let response = match settings.first_byte_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, response).await.map_err(|_| {
+            ApiError::Timeout(format!(
+                "{} did not respond within {}s",
+                model_config.alias(),
+                timeout.as_secs()
+            ))
+        })?,
+        None => response.await,
+    };
+
+    let mut response = response.context("Error passing on request")?;
 
-    let headers = take(response.headers_mut());
+    let mut headers = take(response.headers_mut());
     let extensions = take(response.extensions_mut());
 
-    let mut response = Response::new(Body::from_stream(response.bytes_stream()));
+    headers.extend(limits.status.headers());
+
+    let rate_limits = state.rate_limits().clone();
+    let usage_store = state.usage_store().clone();
+    let alias = model_config.alias().to_string();
+    let upstream = base_url.as_str().trim_end_matches('/').to_string();
+    let client = caller.name().map(str::to_string);
+
+    let mut usage_tracker = UsageTracker::new(&headers, strip_stream_usage, move |usage| {
+        rate_limits.record_tokens(&limits.scopes, usage.total());
+        usage_store.record(UsageRecord {
+            timestamp: Utc::now(),
+            alias,
+            upstream,
+            client,
+            usage,
+        });
+    });
+
+    // Moving the guard into the stream keeps the model active until the body is fully sent.
+    // `None` marks the end of the upstream body, after which the tracker passes on what's left.
+    let mut response = Response::new(Body::from_stream(
+        response
+            .bytes_stream()
+            .map(Some)
+            .chain(tokio_stream::once(None))
+            .map(move |chunk| {
+                let _guard = &guard;
+
+                match chunk {
+                    Some(chunk) => chunk.map(|chunk| usage_tracker.inspect(chunk)),
+                    None => Ok(usage_tracker.finish()),
+                }
+            })
+            .filter(|chunk| !chunk.as_ref().is_ok_and(|chunk| chunk.is_empty())),
+    ));
 
     *response.headers_mut() = headers;
     *response.extensions_mut() = extensions;
@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use crate::config::{Config, ExternalConfig, HttpClientConfig, ModelConfig, ModelTypeConfig};
+
+/// Upstream HTTP clients shared between requests so connections are pooled, one per distinct
+/// set of client settings.
+#[derive(Clone, Default)]
+pub struct HttpClients {
+    defaults: Arc<RwLock<HttpClientConfig>>,
+    clients: Arc<Mutex<HashMap<HttpClientConfig, reqwest::Client>>>,
+}
+
+impl HttpClients {
+    /// Takes the config-wide settings, called whenever the config is (re)loaded.
+    pub fn set_defaults(&self, config: &Config) {
+        *self.defaults.write().unwrap() = config.http_client.clone().unwrap_or_default();
+    }
+
+    /// Settings of the model, falling back to its provider's and the config-wide settings.
+    pub fn settings(&self, model_config: &ModelConfig) -> HttpClientConfig {
+        let defaults = self.defaults.read().unwrap().clone();
+
+        let provider = match &model_config.config {
+            ModelTypeConfig::External(ExternalConfig::ProviderAndModel(x)) => {
+                x.provider.http_client.clone()
+            }
+            _ => None,
+        };
+
+        model_config
+            .http_client
+            .clone()
+            .unwrap_or_default()
+            .or(&provider.unwrap_or_default())
+            .or(&defaults)
+    }
+
+    pub fn get(&self, settings: &HttpClientConfig) -> Result<reqwest::Client> {
+        let mut clients = self.clients.lock().unwrap();
+
+        if let Some(client) = clients.get(settings) {
+            return Ok(client.clone());
+        }
+
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(max_idle) = settings.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+
+        if let Some(secs) = settings.pool_idle_timeout_secs {
+            builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+        }
+
+        if let Some(secs) = settings.connect_timeout_secs {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+
+        if let Some(secs) = settings.timeout_secs {
+            builder = builder.timeout(Duration::from_secs(secs));
+        }
+
+        let client = builder.build().context("Failed building HTTP client")?;
+
+        clients.insert(settings.clone(), client.clone());
+
+        Ok(client)
+    }
+
+    pub fn get_for(
+        &self,
+        model_config: &ModelConfig,
+    ) -> Result<(reqwest::Client, HttpClientConfig)> {
+        let settings = self.settings(model_config);
+
+        Ok((self.get(&settings)?, settings))
+    }
+}
@@ -1,10 +1,13 @@
 use axum::{
     Json, Router,
-    extract::{Path, Query, State},
-    response::{IntoResponse, Sse},
-    routing::get,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response, Sse},
+    routing::{get, post},
 };
+use chrono::Utc;
 use serde::Deserialize;
+use std::net::SocketAddr;
 use tokio_stream::{StreamExt, wrappers::BroadcastStream};
 
 use crate::{
@@ -12,13 +15,16 @@ use crate::{
         result::{ApiError, ApiResult},
         state::ApiState,
     },
-    config::{AliasOrIndex, Config, ModelConfig},
-    models::LogsAndTailReceiver,
+    config::{AliasOrIndex, ModelConfig},
+    models::{LoadedModelStatus, LogsAndTailReceiver, ReloadReport},
+    usage::{UsageReport, parse_time},
 };
 
-pub fn router() -> Router<ApiState> {
+pub fn router(state: ApiState) -> Router<ApiState> {
     Router::new()
         .route("/", get(list_model_configs))
+        .route("/reload", post(reload_config))
+        .route("/usage", get(get_usage))
         .route(
             "/{alias_or_index}",
             get(get_model_config)
@@ -26,11 +32,59 @@ pub fn router() -> Router<ApiState> {
                 .delete(delete_model_config),
         )
         .route("/{alias_or_index}/logs", get(get_model_logs))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Only admin clients may manage the router once `clients` are configured
+async fn require_admin(
+    State(state): State<ApiState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    state
+        .client_auth()
+        .authenticate(request.headers(), address.ip())?
+        .authorize_admin()?;
+
+    Ok(next.run(request).await)
+}
+
+#[axum::debug_handler]
+async fn list_model_configs(State(state): State<ApiState>) -> Json<Vec<LoadedModelStatus>> {
+    Json(state.models().get_loaded_statuses().await)
 }
 
 #[axum::debug_handler]
-async fn list_model_configs(State(state): State<ApiState>) -> Json<Vec<ModelConfig>> {
-    Json(state.models().get_loaded_configs().await)
+async fn reload_config(State(state): State<ApiState>) -> ApiResult<Json<ReloadReport>> {
+    Ok(Json(state.reload().await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    since: Option<String>,
+    until: Option<String>,
+}
+
+#[axum::debug_handler]
+async fn get_usage(
+    State(state): State<ApiState>,
+    Query(UsageQuery { since, until }): Query<UsageQuery>,
+) -> ApiResult<Json<UsageReport>> {
+    let now = Utc::now();
+    let parse = |value: Option<String>| {
+        value
+            .map(|value| parse_time(&value, now))
+            .transpose()
+            .map_err(|err| ApiError::BadRequest(format!("{err:#}")))
+    };
+
+    Ok(Json(
+        state
+            .usage_store()
+            .report(parse(since)?, parse(until)?)
+            .await?,
+    ))
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,7 +100,7 @@ async fn get_model_config(
     let alias = match AliasOrIndex::from(alias_or_index) {
         AliasOrIndex::Alias(alias) => alias,
         AliasOrIndex::Index(index) => {
-            let config = Config::load(state.config_path())?;
+            let config = state.config();
 
             config.get_model_config(index)?.alias().to_string()
         }
@@ -67,9 +121,9 @@ async fn post_model_config(
     Query(TailAndJsonQuery { tail, json }): Query<TailAndJsonQuery>,
     Path(AliasOrIndexPath { alias_or_index }): Path<AliasOrIndexPath>,
 ) -> ApiResult {
-    let config = Config::load(state.config_path())?;
+    let config = state.config();
 
-    let (model_config, logs_and_tail_receiver) =
+    let (load_report, logs_and_tail_receiver) =
         state.models().load(&config, &alias_or_index).await?;
 
     let (tail, json) = (tail.unwrap_or(false), json.unwrap_or(true));
@@ -88,7 +142,7 @@ async fn post_model_config(
         )
         .into_response()
     } else {
-        Json(model_config).into_response()
+        Json(load_report).into_response()
     };
 
     Ok(response)
@@ -102,7 +156,7 @@ async fn delete_model_config(
     let alias = match AliasOrIndex::from(alias_or_index) {
         AliasOrIndex::Alias(alias) => alias,
         AliasOrIndex::Index(index) => {
-            let config = Config::load(state.config_path())?;
+            let config = state.config();
 
             config.get_model_config(index)?.alias().to_string()
         }
@@ -120,7 +174,7 @@ async fn get_model_logs(
     let alias = match AliasOrIndex::from(alias_or_index) {
         AliasOrIndex::Alias(alias) => alias,
         AliasOrIndex::Index(index) => {
-            let config = Config::load(state.config_path())?;
+            let config = state.config();
 
             config.get_model_config(index)?.alias().to_string()
         }
@@ -1,18 +1,41 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, anyhow};
-use axum::{Json, Router, extract::State, routing::get};
+use anyhow::Context;
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Query, State},
+    http::HeaderMap,
+    routing::get,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use tokio::{sync::Mutex, task::JoinSet};
+use url::Url;
+use utils_rs::option::as_bool::AsBool;
 
-use crate::api::{result::ApiResult, state::ApiState};
+use crate::{
+    api::{http_clients::HttpClients, result::ApiResult, state::ApiState},
+    config::ModelConfig,
+    models::ModelState,
+};
 
 pub fn router() -> Router<ApiState> {
     Router::new().route("/models", get(list_v1_models))
 }
 
+/// Base URL and API key of a provider, models sharing both share a model list
+type ProviderKey = (Url, Option<String>);
+
+/// Successfully fetched provider model lists along with when they were fetched
+pub type V1ModelsCache = Arc<Mutex<HashMap<ProviderKey, (Instant, Vec<V1ModelsResponseItem>)>>>;
+
 #[derive(Clone, Serialize, Deserialize)]
-struct V1ModelsResponseItem {
+pub struct V1ModelsResponseItem {
     id: String,
     #[serde(flatten)]
     additional_properties: Map<String, Value>,
@@ -25,72 +48,258 @@ struct V1ModelsResponse {
     additional_properties: Map<String, Value>,
 }
 
+/// Added to every listed model as `herder`
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct HerderModelInfo {
+    loaded: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    state: Option<ModelState>,
+    /// Why the entry couldn't be taken from the provider's model list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UnloadedQuery {
+    unloaded: Option<bool>,
+}
+
+/// Lists from the last config that loaded without errors, so a broken config file or secret
+/// doesn't fail the listing. Providers that fail only flag their own entries.
 #[axum::debug_handler]
-async fn list_v1_models(State(state): State<ApiState>) -> ApiResult<Json<V1ModelsResponse>> {
+async fn list_v1_models(
+    State(state): State<ApiState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(UnloadedQuery { unloaded }): Query<UnloadedQuery>,
+) -> ApiResult<Json<V1ModelsResponse>> {
+    let caller = state.client_auth().authenticate(&headers, address.ip())?;
+    let config = state.config();
+    let options = config.v1_models.clone().unwrap_or_default();
+    let mut statuses = state.models().get_loaded_statuses().await;
+
+    statuses.retain(|status| caller.allows_model(status.config.alias()));
+
+    let ready_configs = statuses
+        .iter()
+        .filter(|status| matches!(status.state, ModelState::Ready))
+        .map(|status| &status.config);
+
+    let provider_model_lists = fetch_provider_model_lists(
+        state.v1_models_cache(),
+        state.http_clients(),
+        ready_configs,
+        options.cache_ttl(),
+        options.timeout(),
+    )
+    .await;
+
     let mut result = V1ModelsResponse::default();
 
-    let mut provider_model_lists = HashMap::new();
-
-    for model_config in state.models().get_loaded_configs().await {
-        let url = model_config
-            .url()
-            .and_then(|url| url.join("/v1/models"))
-            .context("Failed constructing Url")?;
-
-        let key = (
-            model_config
-                .url()
-                .map_err(|err| anyhow!("Error constructing Url: {err}"))?
-                .to_string(),
-            model_config
-                .api_key()
-                .map(|k| k.expose_clone())
-                .unwrap_or_default(),
-        );
-
-        let provider_model_list = match provider_model_lists.get(&key) {
-            Some(response) => response,
-            None => {
-                let mut request = reqwest::Client::new().get(url);
-
-                if let Some(key) = model_config.api_key() {
-                    request = request.bearer_auth(key.expose_ref())
-                }
+    for status in &statuses {
+        // Only ready models can be asked for their model list
+        let listed = match &status.state {
+            ModelState::Ready => Some(find_listed_model(&provider_model_lists, &status.config)),
+            ModelState::Starting | ModelState::Failed { .. } => None,
+        };
 
-                let models = request
-                    .send()
-                    .await
-                    .context("Failed sending request")?
-                    .error_for_status()
-                    .context("Error returned from provider")?
-                    .json::<V1ModelsResponse>()
-                    .await
-                    .context("Failed parsing response")?
-                    .data;
-
-                provider_model_lists.insert(key.clone(), models);
-                provider_model_lists.get(&key).unwrap()
-            }
+        let (item, error) = match listed {
+            Some(Ok(item)) => (item, None),
+            Some(Err(err)) => (placeholder_item(&status.config), Some(err)),
+            None => (placeholder_item(&status.config), None),
         };
 
-        result.data.push(
-            provider_model_list
-                .iter()
-                .find({
-                    let id = model_config.id();
-                    move |model| model.id == id
-                })
-                .cloned()
-                .map(|mut x| {
-                    x.id = model_config.alias().into();
-                    x
-                })
-                .ok_or(anyhow!(
-                    "No model with id '{}' returned from provider of {model_config:#?}",
-                    model_config.id()
-                ))?,
-        );
+        result.data.push(with_herder_info(
+            item,
+            &status.config,
+            HerderModelInfo {
+                loaded: true,
+                state: Some(status.state.clone()),
+                error,
+            },
+        ));
+    }
+
+    if unloaded.or(options.include_unloaded).as_bool() {
+        let mut listed_aliases = statuses
+            .iter()
+            .map(|status| status.config.alias().to_string())
+            .collect::<HashSet<_>>();
+
+        for model_config in &config.models {
+            if !caller.allows_model(model_config.alias()) {
+                continue;
+            }
+
+            if !listed_aliases.insert(model_config.alias().to_string()) {
+                continue;
+            }
+
+            result.data.push(with_herder_info(
+                placeholder_item(model_config),
+                model_config,
+                HerderModelInfo {
+                    loaded: false,
+                    state: None,
+                    error: None,
+                },
+            ));
+        }
     }
 
     Ok(Json(result))
 }
+
+fn find_listed_model(
+    provider_model_lists: &HashMap<ProviderKey, Result<Vec<V1ModelsResponseItem>, String>>,
+    model_config: &ModelConfig,
+) -> Result<V1ModelsResponseItem, String> {
+    let key = provider_key(model_config).map_err(|err| format!("{err:#}"))?;
+
+    provider_model_lists
+        .get(&key)
+        .ok_or("Provider was not asked for its models".to_string())?
+        .clone()?
+        .into_iter()
+        .find(|model| model.id == model_config.id())
+        .ok_or(format!(
+            "No model with id '{}' returned from provider",
+            model_config.id()
+        ))
+}
+
+fn provider_key(model_config: &ModelConfig) -> anyhow::Result<ProviderKey> {
+    Ok((
+        model_config.url().context("Failed constructing Url")?,
+        model_config.api_key().map(|api_key| api_key.expose_clone()),
+    ))
+}
+
+/// Entry for models that aren't (successfully) listed by their provider
+fn placeholder_item(model_config: &ModelConfig) -> V1ModelsResponseItem {
+    V1ModelsResponseItem {
+        id: model_config.alias().to_string(),
+        additional_properties: Map::from_iter([
+            ("object".to_string(), Value::from("model")),
+            ("owned_by".to_string(), Value::from("hrdr")),
+        ]),
+    }
+}
+
+fn with_herder_info(
+    mut item: V1ModelsResponseItem,
+    model_config: &ModelConfig,
+    info: HerderModelInfo,
+) -> V1ModelsResponseItem {
+    item.id = model_config.alias().to_string();
+    item.additional_properties.insert(
+        "herder".to_string(),
+        serde_json::to_value(info).expect("HerderModelInfo serializes"),
+    );
+
+    item
+}
+
+/// Fetches the model lists of all providers of `model_configs` concurrently, reusing cached lists
+/// younger than `cache_ttl`. Failures are returned per provider and not cached.
+async fn fetch_provider_model_lists(
+    cache: &V1ModelsCache,
+    http_clients: &HttpClients,
+    model_configs: impl Iterator<Item = &ModelConfig>,
+    cache_ttl: Duration,
+    timeout: Duration,
+) -> HashMap<ProviderKey, Result<Vec<V1ModelsResponseItem>, String>> {
+    let mut results = HashMap::new();
+    let mut fetches = JoinSet::new();
+
+    {
+        let cache = cache.lock().await;
+
+        for model_config in model_configs {
+            let Ok(key) = provider_key(model_config) else {
+                continue;
+            };
+
+            if results.contains_key(&key) {
+                continue;
+            }
+
+            if let Some((fetched_at, models)) = cache.get(&key)
+                && fetched_at.elapsed() < cache_ttl
+            {
+                results.insert(key, Ok(models.clone()));
+                continue;
+            }
+
+            let client = match http_clients.get_for(model_config) {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    results.insert(key, Err(format!("{err:#}")));
+                    continue;
+                }
+            };
+
+            // Replaced when the fetch completes, also deduplicates fetches
+            results.insert(
+                key.clone(),
+                Err("Failed fetching the model list".to_string()),
+            );
+
+            fetches.spawn(async move {
+                let result =
+                    tokio::time::timeout(timeout, fetch_provider_model_list(&client, &key))
+                        .await
+                        .unwrap_or(Err(format!(
+                            "Provider did not respond within {}s",
+                            timeout.as_secs()
+                        )));
+
+                (key, result)
+            });
+        }
+    }
+
+    while let Some(fetched) = fetches.join_next().await {
+        let Ok((key, result)) = fetched else {
+            continue;
+        };
+
+        if let Ok(models) = &result {
+            cache
+                .lock()
+                .await
+                .insert(key.clone(), (Instant::now(), models.clone()));
+        }
+
+        results.insert(key, result);
+    }
+
+    results
+}
+
+async fn fetch_provider_model_list(
+    client: &reqwest::Client,
+    (url, api_key): &ProviderKey,
+) -> Result<Vec<V1ModelsResponseItem>, String> {
+    let url = url
+        .join("/v1/models")
+        .map_err(|err| format!("Failed constructing Url: {err}"))?;
+
+    let mut request = client.get(url);
+
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key)
+    }
+
+    Ok(request
+        .send()
+        .await
+        .map_err(|err| format!("Failed sending request: {err}"))?
+        .error_for_status()
+        .map_err(|err| format!("Error returned from provider: {err}"))?
+        .json::<V1ModelsResponse>()
+        .await
+        .map_err(|err| format!("Failed parsing response: {err}"))?
+        .data)
+}
@@ -0,0 +1,483 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    api::auth::Caller,
+    config::{ModelConfig, RateLimitConfig},
+};
+
+const STORE_FILE_NAME: &str = "rate-limits.json";
+/// How often changed counters are written to disk
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const MINUTE_SECS: u64 = 60;
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Limits along with what they apply to, e.g. `client:alice` or `alias:qwen`
+pub struct RateLimitScope {
+    key: String,
+    limits: RateLimitConfig,
+}
+
+impl RateLimitScope {
+    /// Scope of the limits configured for the caller
+    pub fn for_client(caller: &Caller) -> Option<RateLimitScope> {
+        match caller {
+            Caller::Anyone => None,
+            Caller::Client { name, config } => config.limits.clone().map(|limits| RateLimitScope {
+                key: format!("client:{name}"),
+                limits,
+            }),
+        }
+    }
+
+    pub fn for_alias(model_config: &ModelConfig) -> Option<RateLimitScope> {
+        model_config.limits.clone().map(|limits| RateLimitScope {
+            key: format!("alias:{}", model_config.alias()),
+            limits,
+        })
+    }
+}
+
+/// Scopes a request was counted against, along with their tightest remaining capacity
+pub struct AcquiredLimits {
+    pub scopes: Vec<RateLimitScope>,
+    pub status: RateLimitStatus,
+}
+
+impl AcquiredLimits {
+    /// Counts the request against every scope, or against none of them if one is exhausted
+    pub fn acquire(
+        rate_limits: &RateLimits,
+        scopes: Vec<RateLimitScope>,
+    ) -> Result<Self, RateLimitExceeded> {
+        let status = rate_limits.acquire(&scopes)?;
+
+        Ok(Self { scopes, status })
+    }
+}
+
+/// Usage within the current minute and day, both counted since the unix epoch
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Counter {
+    minute: u64,
+    requests: u64,
+    day: u64,
+    tokens: u64,
+}
+
+impl Counter {
+    fn roll(&mut self, now: u64) {
+        if self.minute != now / MINUTE_SECS {
+            self.minute = now / MINUTE_SECS;
+            self.requests = 0;
+        }
+
+        if self.day != now / DAY_SECS {
+            self.day = now / DAY_SECS;
+            self.tokens = 0;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    limit: u64,
+    remaining: u64,
+    reset: Duration,
+}
+
+impl Window {
+    fn new(limit: u64, used: u64, now: u64, length: u64) -> Self {
+        Self {
+            limit,
+            remaining: limit.saturating_sub(used),
+            reset: Duration::from_secs(length - now % length),
+        }
+    }
+}
+
+/// Remaining capacity of the most constrained scope, sent as `x-ratelimit-*` headers
+#[derive(Debug, Default)]
+pub struct RateLimitStatus {
+    requests: Option<Window>,
+    tokens: Option<Window>,
+}
+
+impl RateLimitStatus {
+    fn tighten(current: &mut Option<Window>, window: Window) {
+        if current.is_none_or(|current| window.remaining < current.remaining) {
+            *current = Some(window);
+        }
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, window) in [("requests", self.requests), ("tokens", self.tokens)] {
+            let Some(window) = window else {
+                continue;
+            };
+
+            for (prefix, value) in [
+                ("x-ratelimit-limit", window.limit.to_string()),
+                ("x-ratelimit-remaining", window.remaining.to_string()),
+                ("x-ratelimit-reset", format_reset(window.reset)),
+            ] {
+                headers.insert(
+                    HeaderName::try_from(format!("{prefix}-{name}")).expect("Valid header name"),
+                    HeaderValue::try_from(value).expect("Valid header value"),
+                );
+            }
+        }
+
+        headers
+    }
+}
+
+/// Formats like OpenAI's reset headers, e.g. `45s`, `6m0s` or `3h2m1s`
+fn format_reset(reset: Duration) -> String {
+    let secs = reset.as_secs();
+
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s}s"),
+        (h, m, s) => format!("{h}h{m}m{s}s"),
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimitExceeded {
+    pub message: String,
+    /// `requests` or `tokens`
+    pub kind: &'static str,
+    pub retry_after: Duration,
+    pub status: RateLimitStatus,
+}
+
+struct Store {
+    path: PathBuf,
+    counters: HashMap<String, Counter>,
+    /// Whether the counters changed since they were last written
+    dirty: bool,
+}
+
+/// Request and token counters per scope, kept in memory and flushed to disk periodically so
+/// limits hold across restarts
+#[derive(Clone)]
+pub struct RateLimits {
+    store: Arc<Mutex<Store>>,
+    /// Serializes flushes so an older snapshot can't overwrite a newer one
+    flushing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl RateLimits {
+    /// Reads the counters persisted in `state_dir`, starting afresh if there are none.
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join(STORE_FILE_NAME);
+
+        let counters = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                eprintln!("Ignoring unreadable rate limit counters in {path:?}: {err}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            store: Arc::new(Mutex::new(Store {
+                path,
+                counters,
+                dirty: false,
+            })),
+            flushing: Default::default(),
+        }
+    }
+
+    pub fn spawn_flusher(&self) {
+        let rate_limits = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                rate_limits.flush().await;
+            }
+        });
+    }
+
+    /// Writes the counters if they changed since the last flush, logging failures since requests
+    /// shouldn't fail over them.
+    pub async fn flush(&self) {
+        let _flushing = self.flushing.lock().await;
+
+        let (path, counters) = {
+            let mut store = self.store.lock().unwrap();
+
+            if !store.dirty {
+                return;
+            }
+
+            let today = unix_now() / DAY_SECS;
+
+            store.counters.retain(|_, counter| counter.day == today);
+            store.dirty = false;
+
+            (store.path.clone(), store.counters.clone())
+        };
+
+        let write_path = path.clone();
+        let written = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            if let Some(dir) = write_path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+
+            let temp_path = write_path.with_extension("json.tmp");
+
+            std::fs::write(&temp_path, serde_json::to_vec(&counters)?)?;
+            std::fs::rename(&temp_path, &write_path)?;
+
+            Ok(())
+        })
+        .await;
+
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                eprintln!("Failed to persist rate limit counters to {path:?}: {err:#}")
+            }
+            Err(err) => eprintln!("Failed to persist rate limit counters to {path:?}: {err}"),
+        }
+    }
+
+    /// Counts a request against every scope, unless any of them is out of requests or tokens.
+    pub fn acquire(&self, scopes: &[RateLimitScope]) -> Result<RateLimitStatus, RateLimitExceeded> {
+        if scopes.is_empty() {
+            return Ok(RateLimitStatus::default());
+        }
+
+        let now = unix_now();
+        let mut store = self.store.lock().unwrap();
+
+        for scope in scopes {
+            let counter = store.counters.entry(scope.key.clone()).or_default();
+
+            counter.roll(now);
+
+            let exceeded = [
+                (
+                    "requests",
+                    "requests per minute",
+                    scope.limits.requests_per_minute,
+                    counter.requests,
+                    MINUTE_SECS,
+                ),
+                (
+                    "tokens",
+                    "tokens per day",
+                    scope.limits.tokens_per_day,
+                    counter.tokens,
+                    DAY_SECS,
+                ),
+            ]
+            .into_iter()
+            .find_map(|(kind, description, limit, used, length)| {
+                let limit = limit.filter(|limit| used >= *limit)?;
+                let window = Window::new(limit, used, now, length);
+
+                Some(RateLimitExceeded {
+                    message: format!(
+                        "Rate limit reached for {} on {description}: Limit {limit}, Used {used}. Please try again in {}.",
+                        scope.key,
+                        format_reset(window.reset)
+                    ),
+                    kind,
+                    retry_after: window.reset,
+                    status: match kind {
+                        "requests" => RateLimitStatus {
+                            requests: Some(window),
+                            tokens: None,
+                        },
+                        _ => RateLimitStatus {
+                            requests: None,
+                            tokens: Some(window),
+                        },
+                    },
+                })
+            });
+
+            if let Some(exceeded) = exceeded {
+                return Err(exceeded);
+            }
+        }
+
+        let mut status = RateLimitStatus::default();
+
+        for scope in scopes {
+            let counter = store.counters.entry(scope.key.clone()).or_default();
+
+            counter.requests += 1;
+
+            if let Some(limit) = scope.limits.requests_per_minute {
+                RateLimitStatus::tighten(
+                    &mut status.requests,
+                    Window::new(limit, counter.requests, now, MINUTE_SECS),
+                );
+            }
+
+            if let Some(limit) = scope.limits.tokens_per_day {
+                RateLimitStatus::tighten(
+                    &mut status.tokens,
+                    Window::new(limit, counter.tokens, now, DAY_SECS),
+                );
+            }
+        }
+
+        store.dirty = true;
+
+        Ok(status)
+    }
+
+    /// Counts the tokens of a finished request against every scope.
+    pub fn record_tokens(&self, scopes: &[RateLimitScope], tokens: u64) {
+        if scopes.is_empty() || tokens == 0 {
+            return;
+        }
+
+        let now = unix_now();
+        let mut store = self.store.lock().unwrap();
+
+        for scope in scopes {
+            let counter = store.counters.entry(scope.key.clone()).or_default();
+
+            counter.roll(now);
+            counter.tokens += tokens;
+        }
+
+        store.dirty = true;
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(
+        key: &str,
+        requests_per_minute: Option<u64>,
+        tokens_per_day: Option<u64>,
+    ) -> RateLimitScope {
+        RateLimitScope {
+            key: key.to_string(),
+            limits: RateLimitConfig {
+                requests_per_minute,
+                tokens_per_day,
+            },
+        }
+    }
+
+    fn rate_limits() -> RateLimits {
+        RateLimits::load(Path::new("/nonexistent"))
+    }
+
+    fn requests(rate_limits: &RateLimits, key: &str) -> u64 {
+        rate_limits.store.lock().unwrap().counters[key].requests
+    }
+
+    #[test]
+    fn roll_resets_requests_every_minute_and_tokens_every_day() {
+        let mut counter = Counter {
+            minute: 10,
+            requests: 5,
+            day: 0,
+            tokens: 100,
+        };
+
+        counter.roll(10 * MINUTE_SECS + 59);
+        assert_eq!((counter.requests, counter.tokens), (5, 100));
+
+        counter.roll(11 * MINUTE_SECS);
+        assert_eq!(
+            (counter.minute, counter.requests, counter.tokens),
+            (11, 0, 100)
+        );
+
+        counter.roll(DAY_SECS);
+        assert_eq!((counter.day, counter.tokens), (1, 0));
+    }
+
+    #[test]
+    fn requests_are_rejected_once_the_limit_is_reached() {
+        let rate_limits = rate_limits();
+        let scopes = [scope("client:a", Some(2), None)];
+
+        assert!(rate_limits.acquire(&scopes).is_ok());
+        assert!(rate_limits.acquire(&scopes).is_ok());
+
+        let exceeded = rate_limits.acquire(&scopes).unwrap_err();
+
+        assert_eq!(exceeded.kind, "requests");
+        assert_eq!(requests(&rate_limits, "client:a"), 2);
+    }
+
+    #[test]
+    fn rejected_requests_are_not_counted_against_other_scopes() {
+        let rate_limits = rate_limits();
+        let scopes = [
+            scope("alias:m", Some(5), None),
+            scope("client:a", Some(1), None),
+        ];
+
+        assert!(rate_limits.acquire(&scopes).is_ok());
+        assert!(rate_limits.acquire(&scopes).is_err());
+        assert_eq!(requests(&rate_limits, "alias:m"), 1);
+    }
+
+    #[test]
+    fn recorded_tokens_count_against_the_daily_quota() {
+        let rate_limits = rate_limits();
+        let scopes = [scope("client:a", None, Some(100))];
+
+        assert!(rate_limits.acquire(&scopes).is_ok());
+        rate_limits.record_tokens(&scopes, 100);
+
+        assert_eq!(rate_limits.acquire(&scopes).unwrap_err().kind, "tokens");
+    }
+
+    #[test]
+    fn status_reports_the_tightest_scope() {
+        let rate_limits = rate_limits();
+        let scopes = [
+            scope("alias:m", Some(10), None),
+            scope("client:a", Some(3), None),
+        ];
+
+        let status = rate_limits.acquire(&scopes).unwrap();
+        let headers = status.headers();
+
+        assert_eq!(headers["x-ratelimit-limit-requests"], "3");
+        assert_eq!(headers["x-ratelimit-remaining-requests"], "2");
+        assert!(!headers.contains_key("x-ratelimit-limit-tokens"));
+    }
+
+    #[test]
+    fn resets_are_formatted_like_openai() {
+        assert_eq!(format_reset(Duration::from_secs(45)), "45s");
+        assert_eq!(format_reset(Duration::from_secs(360)), "6m0s");
+        assert_eq!(format_reset(Duration::from_secs(3 * 3600 + 121)), "3h2m1s");
+    }
+}
@@ -1,7 +1,16 @@
 use axum::{
-    http::StatusCode,
+    Json,
+    http::{
+        StatusCode,
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+    },
     response::{IntoResponse, Response},
 };
+use serde_json::json;
+
+use crate::api::rate_limits::RateLimitExceeded;
+
+const RETRY_AFTER_SECS: u64 = 1;
 
 pub type ApiResult<T = Response> = Result<T, ApiError>;
 
@@ -11,22 +20,75 @@ pub enum ApiError {
     NotFound(String),
     #[error("Request rejected: {0}")]
     BadRequest(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Model unavailable: {0}")]
+    Unavailable(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("{}", .0.message)]
+    RateLimited(RateLimitExceeded),
+    #[error("Upstream timed out: {0}")]
+    Timeout(String),
     #[error(transparent)]
     Internal(#[from] anyhow::Error),
 }
 
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::TooManyRequests(_) | ApiError::RateLimited(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         eprintln!("Responding with error: {self:#?}");
 
-        (
-            match &self {
-                ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
-                ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
-                ApiError::NotFound(_) => StatusCode::NOT_FOUND,
-            },
-            self.to_string(),
-        )
-            .into_response()
+        let status = self.status();
+
+        match &self {
+            ApiError::TooManyRequests(_) => (
+                status,
+                [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                self.to_string(),
+            )
+                .into_response(),
+            ApiError::RateLimited(exceeded) => {
+                let mut headers = exceeded.status.headers();
+
+                headers.insert(RETRY_AFTER, exceeded.retry_after.as_secs().max(1).into());
+
+                (
+                    status,
+                    headers,
+                    Json(json!({
+                        "error": {
+                            "message": exceeded.message,
+                            "type": exceeded.kind,
+                            "param": null,
+                            "code": "rate_limit_exceeded",
+                        }
+                    })),
+                )
+                    .into_response()
+            }
+            ApiError::Unauthorized(_) => {
+                (status, [(WWW_AUTHENTICATE, "Bearer")], self.to_string()).into_response()
+            }
+            _ => (status, self.to_string()).into_response(),
+        }
     }
 }
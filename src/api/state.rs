@@ -1,21 +1,82 @@
 use anyhow::Result;
-use std::path::{Path, PathBuf};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    fs::metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
 use utils_rs::option::as_bool::AsBool;
 
-use crate::{config::Config, models::Models};
+use crate::{
+    api::{
+        auth::ClientAuth, http_clients::HttpClients, open_ai::V1ModelsCache,
+        rate_limits::RateLimits,
+    },
+    config::{Config, watched_paths},
+    models::{Models, ReloadReport},
+    usage::UsageStore,
+};
+
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct ApiState {
     config_path: PathBuf,
+    /// Last config that loaded without errors, so requests don't re-read files and secrets
+    config: Arc<RwLock<Arc<Config>>>,
     models: Models,
+    v1_models_cache: V1ModelsCache,
+    http_clients: HttpClients,
+    client_auth: ClientAuth,
+    rate_limits: RateLimits,
+    usage_store: UsageStore,
 }
 
 impl ApiState {
     pub async fn init(config_path: PathBuf) -> Result<Self> {
         let models = Models::default();
-        let config = Config::load(&config_path)?;
+        let config = load_config(&config_path).await?;
+        let http_clients = HttpClients::default();
+        let client_auth = ClientAuth::default();
+        let rate_limits = RateLimits::load(&config.state_dir());
+        let usage_store = UsageStore::new(&config.state_dir());
+
+        http_clients.set_defaults(&config);
+        client_auth.set_clients(&config);
+
+        models.spawn_idle_reaper();
+        rate_limits.spawn_flusher();
+
+        let state = Self {
+            config_path,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            models,
+            v1_models_cache: Default::default(),
+            http_clients,
+            client_auth,
+            rate_limits,
+            usage_store,
+        };
+
+        state.spawn_config_watcher();
 
-        if config.load_defaults_on_launch.as_bool() {
+        Ok(state)
+    }
+
+    /// Loads the default models in the background if `load-defaults-on-launch` is set, logging
+    /// the ones that fail to load.
+    pub fn spawn_default_loads(&self) {
+        let config = self.config();
+
+        if !config.load_defaults_on_launch.as_bool() {
+            return;
+        }
+
+        let models = self.models.clone();
+
+        tokio::spawn(async move {
             let default_aliases = config
                 .models
                 .iter()
@@ -28,20 +89,146 @@ impl ApiState {
                     model_config.alias(),
                     model_config.config
                 );
-                models.load(&config, index).await?;
+
+                if let Err(err) = models.load(&config, index).await {
+                    eprintln!(
+                        "Failed to load default model for alias '{}': {err:#}",
+                        model_config.alias()
+                    );
+                }
             }
-        }
+        });
+    }
 
-        Ok(Self {
-            config_path,
-            models,
-        })
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        let config = load_config(&self.config_path).await?;
+        let previous = self.config();
+
+        self.http_clients.set_defaults(&config);
+        self.client_auth.set_clients(&config);
+
+        let report = self.models.reload(&previous, &config).await;
+        let settings = describe_setting_changes(&previous, &config);
+
+        *self.config.write().unwrap() = Arc::new(config);
+
+        report.map(|report| ReloadReport { settings, ..report })
+    }
+
+    /// Polls the config file and the files it includes for modifications and reloads when any of
+    /// them change.
+    fn spawn_config_watcher(&self) {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let modified_at = |config_path: &Path| -> Vec<(PathBuf, Option<SystemTime>)> {
+                watched_paths(config_path)
+                    .into_iter()
+                    .map(|path| {
+                        let modified_at = metadata(&path).ok().and_then(|m| m.modified().ok());
+
+                        (path, modified_at)
+                    })
+                    .collect()
+            };
+
+            let mut last_modified_at = modified_at(&state.config_path);
+            let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let current_modified_at = modified_at(&state.config_path);
+
+                if current_modified_at == last_modified_at {
+                    continue;
+                }
+
+                last_modified_at = current_modified_at;
+
+                println!(
+                    "Config file {:?} or its includes changed, reloading",
+                    state.config_path
+                );
+
+                match state.reload().await {
+                    Ok(report) => println!("Reloaded config: {report:?}"),
+                    Err(err) => {
+                        eprintln!("Failed to reload config, keeping the current models: {err:#}")
+                    }
+                }
+            }
+        });
     }
 
     pub fn models(&self) -> &Models {
         &self.models
     }
-    pub fn config_path(&self) -> &Path {
-        &self.config_path
+    pub fn v1_models_cache(&self) -> &V1ModelsCache {
+        &self.v1_models_cache
+    }
+    pub fn http_clients(&self) -> &HttpClients {
+        &self.http_clients
+    }
+    pub fn client_auth(&self) -> &ClientAuth {
+        &self.client_auth
+    }
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
     }
+    pub fn usage_store(&self) -> &UsageStore {
+        &self.usage_store
+    }
+    /// Writes state kept in memory before the router exits.
+    pub async fn shutdown(&self) {
+        self.rate_limits.flush().await;
+        self.usage_store.flush().await;
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+}
+
+/// Parses the config on a blocking thread, as reading secrets may run commands.
+async fn load_config(config_path: &Path) -> Result<Config> {
+    let config_path = config_path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || Config::load(&config_path)).await?
+}
+
+/// Describes how the top-level settings that differ between `previous` and `config` take effect.
+/// Settings that only shape model configs are covered by the models being restarted.
+fn describe_setting_changes(previous: &Config, config: &Config) -> Vec<String> {
+    let (Ok(Value::Object(previous)), Ok(Value::Object(config))) =
+        (serde_json::to_value(previous), serde_json::to_value(config))
+    else {
+        return Vec::new();
+    };
+
+    previous
+        .keys()
+        .chain(config.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| {
+            !matches!(
+                key.as_str(),
+                "$schema" | "models" | "include" | "templates" | "defaults" | "providers"
+            ) && previous.get(*key) != config.get(*key)
+        })
+        .map(|key| {
+            let effect = match key.as_str() {
+                "idle-timeout-secs" => "applied to loaded models",
+                "llama-cpp-binary" => "llama-cpp models using it were restarted",
+                "budget" | "groups" | "port-range" => "applies to models loaded from now on",
+                "load-defaults-on-launch" | "state-dir" | "detach" => {
+                    "takes effect when the router is restarted"
+                }
+                _ => "applied",
+            };
+
+            format!("{key}: {effect}")
+        })
+        .collect()
 }
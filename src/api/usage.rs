@@ -0,0 +1,243 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, header::CONTENT_TYPE},
+};
+use serde_json::Value;
+
+use crate::usage::Usage;
+
+/// Non-streamed JSON bodies larger than this aren't inspected for `usage`
+const MAX_JSON_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+enum BodyFormat {
+    Json,
+    EventStream,
+    Other,
+}
+
+/// Picks the `usage` out of a response body while it is streamed to the client, from the whole
+/// body of JSON responses or the last `data:` event that has one. `on_usage` is called when the
+/// tracker is dropped along with the body, also if the client disconnected early, and with zero
+/// usage for responses without one so every request is counted.
+pub struct UsageTracker {
+    format: BodyFormat,
+    buffer: Vec<u8>,
+    usage: Option<Usage>,
+    /// Whether to drop the final event that only carries `usage`, for clients that didn't ask for it
+    strip_usage_events: bool,
+    /// Whether the lines of the current event are being dropped
+    dropping_event: bool,
+    on_usage: Option<Box<dyn FnOnce(Usage) + Send>>,
+}
+
+impl UsageTracker {
+    pub fn new(
+        headers: &HeaderMap,
+        strip_usage_events: bool,
+        on_usage: impl FnOnce(Usage) + Send + 'static,
+    ) -> Self {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let format = if content_type.contains("text/event-stream") {
+            BodyFormat::EventStream
+        } else if content_type.contains("json") {
+            BodyFormat::Json
+        } else {
+            BodyFormat::Other
+        };
+
+        Self {
+            format,
+            buffer: Vec::new(),
+            usage: None,
+            strip_usage_events,
+            dropping_event: false,
+            on_usage: Some(Box::new(on_usage)),
+        }
+    }
+
+    /// Returns the part of the chunk to pass on to the client. When stripping usage events only
+    /// complete lines are passed on.
+    pub fn inspect(&mut self, chunk: Bytes) -> Bytes {
+        match self.format {
+            BodyFormat::Json if self.buffer.len() + chunk.len() > MAX_JSON_BODY_BYTES => {
+                self.format = BodyFormat::Other;
+                self.buffer = Vec::new();
+                chunk
+            }
+            BodyFormat::Json => {
+                self.buffer.extend_from_slice(&chunk);
+                chunk
+            }
+            BodyFormat::EventStream => {
+                self.buffer.extend_from_slice(&chunk);
+
+                let mut passed_on = Vec::new();
+
+                while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                    let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+
+                    if let Some(data) = line.strip_prefix(b"data:")
+                        && let Ok(event) = serde_json::from_slice::<Value>(data.trim_ascii())
+                        && let Some(usage) = Usage::from_value(&event)
+                    {
+                        self.usage = Some(usage);
+
+                        // The extra chunk streamed for `include_usage` has no choices
+                        self.dropping_event = self.strip_usage_events
+                            && event
+                                .get("choices")
+                                .and_then(Value::as_array)
+                                .is_some_and(Vec::is_empty);
+                    }
+
+                    if !self.dropping_event {
+                        passed_on.extend_from_slice(&line);
+                    }
+
+                    if line.trim_ascii().is_empty() {
+                        self.dropping_event = false;
+                    }
+                }
+
+                if self.strip_usage_events {
+                    Bytes::from(passed_on)
+                } else {
+                    chunk
+                }
+            }
+            BodyFormat::Other => chunk,
+        }
+    }
+
+    /// Returns what's left to pass on once the body has ended, the last line of an event stream
+    /// that doesn't end with a newline when stripping usage events.
+    pub fn finish(&mut self) -> Bytes {
+        match self.format {
+            BodyFormat::EventStream if self.strip_usage_events && !self.dropping_event => {
+                Bytes::from(std::mem::take(&mut self.buffer))
+            }
+            _ => Bytes::new(),
+        }
+    }
+}
+
+impl Drop for UsageTracker {
+    fn drop(&mut self) {
+        if let BodyFormat::Json = self.format
+            && let Ok(body) = serde_json::from_slice::<Value>(&self.buffer)
+        {
+            self.usage = Usage::from_value(&body);
+        }
+
+        if let Some(on_usage) = self.on_usage.take() {
+            on_usage(self.usage.unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    const CONTENT: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n";
+    const USAGE: &str = "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2,\"total_tokens\":5}}\n\n";
+    const DONE: &str = "data: [DONE]\n\n";
+
+    /// Feeds the chunks through a tracker, returning what's passed on and the reported usage
+    fn track(content_type: &str, strip_usage_events: bool, chunks: &[&str]) -> (String, Usage) {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+
+        let reported = Arc::new(Mutex::new(None));
+        let mut tracker = UsageTracker::new(&headers, strip_usage_events, {
+            let reported = reported.clone();
+            move |usage| *reported.lock().unwrap() = Some(usage)
+        });
+
+        let mut passed_on = Vec::new();
+
+        for chunk in chunks {
+            passed_on.extend_from_slice(&tracker.inspect(Bytes::from(chunk.to_string())));
+        }
+
+        passed_on.extend_from_slice(&tracker.finish());
+
+        drop(tracker);
+
+        let usage = reported
+            .lock()
+            .unwrap()
+            .take()
+            .expect("Usage is reported on drop");
+
+        (String::from_utf8(passed_on).unwrap(), usage)
+    }
+
+    #[test]
+    fn usage_is_read_from_events_split_across_chunks() {
+        let stream = [CONTENT, USAGE, DONE].concat();
+        let (first, rest) = stream.split_at(CONTENT.len() + 20);
+
+        let (passed_on, usage) = track("text/event-stream", false, &[first, rest]);
+
+        assert_eq!(passed_on, stream);
+        assert_eq!(usage.total(), 5);
+        assert_eq!(usage.prompt_tokens, 3);
+    }
+
+    #[test]
+    fn usage_events_are_stripped_when_not_requested() {
+        let stream = [CONTENT, USAGE, DONE].concat();
+        let (first, rest) = stream.split_at(CONTENT.len() + 20);
+
+        let (passed_on, usage) = track("text/event-stream", true, &[first, rest]);
+
+        assert_eq!(passed_on, [CONTENT, DONE].concat());
+        assert_eq!(usage.total(), 5);
+    }
+
+    #[test]
+    fn trailing_lines_without_a_newline_are_passed_on() {
+        let (passed_on, _) = track("text/event-stream", true, &[CONTENT, USAGE, "data: [DONE]"]);
+
+        assert_eq!(passed_on, [CONTENT, "data: [DONE]"].concat());
+    }
+
+    #[test]
+    fn usage_on_content_events_is_not_stripped() {
+        let event = "data: {\"choices\":[{}],\"usage\":{\"total_tokens\":7}}\n\n";
+
+        let (passed_on, usage) = track("text/event-stream", true, &[event, DONE]);
+
+        assert_eq!(passed_on, [event, DONE].concat());
+        assert_eq!(usage.total(), 7);
+    }
+
+    #[test]
+    fn usage_is_read_from_json_bodies() {
+        let body = r#"{"choices":[],"usage":{"prompt_tokens":4,"completion_tokens":6}}"#;
+        let (first, rest) = body.split_at(10);
+
+        let (passed_on, usage) = track("application/json", false, &[first, rest]);
+
+        assert_eq!(passed_on, body);
+        assert_eq!(usage.total(), 10);
+    }
+
+    #[test]
+    fn responses_without_usage_report_zero() {
+        let (_, usage) = track("text/event-stream", false, &[CONTENT, DONE]);
+        assert_eq!(usage.total(), 0);
+
+        let (_, usage) = track("text/plain", false, &["usage"]);
+        assert_eq!(usage.total(), 0);
+    }
+}
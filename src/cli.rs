@@ -21,9 +21,24 @@ pub struct ServeArgs {
     pub port: u16,
 }
 
+#[derive(Debug, Args)]
+pub struct RouterArgs {
+    #[clap(long, short, default_value_t = 3100)]
+    pub port: u16,
+    /// Key of an admin client, needed once the router has `clients`
+    #[clap(long, short)]
+    pub key: Option<String>,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum CliCommand {
+    /// Print the JSON schema of the config, which also validates YAML, TOML and JSON5 configs in
+    /// editors that support it, e.g. with `# yaml-language-server: $schema=<path>`
     Schema,
+    Check {
+        #[clap(long, short)]
+        config_path: Option<PathBuf>,
+    },
     Serve {
         #[clap(flatten)]
         args: ServeArgs,
@@ -35,6 +50,18 @@ pub enum CliCommand {
         #[clap(long, short)]
         config_path: Option<PathBuf>,
     },
+    Reload(RouterArgs),
+    /// Print the tokens used by proxied requests per alias, upstream and client
+    Usage {
+        #[clap(flatten)]
+        router: RouterArgs,
+        /// RFC 3339 timestamp, YYYY-MM-DD date or a duration ago like 24h or 7d
+        #[clap(long, short)]
+        since: Option<String>,
+        /// Same formats as `since`
+        #[clap(long, short)]
+        until: Option<String>,
+    },
     Models {
         #[clap(long, short)]
         config_path: Option<PathBuf>,
@@ -47,29 +74,32 @@ pub enum CliCommand {
 pub enum ModelCommand {
     List,
     Config {
+        /// Print the command line spawned models are started with instead of the config
+        #[clap(long, action)]
+        argv: bool,
         alias_or_index: Option<String>,
     },
+    Plan {
+        alias_or_index: String,
+    },
     Load {
-        #[clap(long, short, default_value_t = 3100)]
-        port: u16,
+        #[clap(flatten)]
+        router: RouterArgs,
         #[clap(long, short, action)]
         tail: bool,
         #[clap(long, short, action)]
         json: bool,
         alias_or_index: String,
     },
-    Loaded {
-        #[clap(long, short, default_value_t = 3100)]
-        port: u16,
-    },
+    Loaded(RouterArgs),
     Unload {
-        #[clap(long, short, default_value_t = 3100)]
-        port: u16,
+        #[clap(flatten)]
+        router: RouterArgs,
         alias_or_index: String,
     },
     Logs {
-        #[clap(long, short, default_value_t = 3100)]
-        port: u16,
+        #[clap(flatten)]
+        router: RouterArgs,
         #[clap(long, short, action)]
         tail: bool,
         #[clap(long, short, action)]
@@ -1,21 +1,35 @@
 use std::path::Path;
 
 use crate::{
-    config::{Config, ModelConfig, ModelTypeConfig},
-    models::Log,
+    config::{Config, LoadPlanStep, ModelConfig, ModelTypeConfig},
+    models::{LoadReport, LoadedModelStatus, Log, ReloadReport, launch_command},
+    usage::UsageReport,
 };
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use reqwest_sse::EventSource;
 use tokio::runtime::Runtime;
 use tokio_stream::StreamExt;
 use utils_rs::{option::as_bool::AsBool, prelude::*};
 
-pub fn load_sync(alias_or_index: String, port: u16, tail: bool, json: bool) -> Result<()> {
-    Runtime::new()?.block_on(async { load(alias_or_index, port, tail, json).await })
+pub fn load_sync(
+    alias_or_index: String,
+    port: u16,
+    key: Option<String>,
+    tail: bool,
+    json: bool,
+) -> Result<()> {
+    Runtime::new()?.block_on(async { load(alias_or_index, port, key, tail, json).await })
 }
 
-pub async fn load(alias_or_index: String, port: u16, tail: bool, json: bool) -> Result<()> {
-    let response = reqwest::Client::new()
+pub async fn load(
+    alias_or_index: String,
+    port: u16,
+    key: Option<String>,
+    tail: bool,
+    json: bool,
+) -> Result<()> {
+    let response = herder_client(key)?
         .post(format!(
             "http://localhost:{port}/herder/{alias_or_index}?tail={}&json={}",
             tail.as_str(),
@@ -32,20 +46,32 @@ pub async fn load(alias_or_index: String, port: u16, tail: bool, json: bool) ->
             println!("{}", event.data);
         }
     } else {
-        let model_config = response.json::<ModelConfig>().await?;
+        let load_report = response.json::<LoadReport>().await?;
 
-        println!("{model_config:#?}");
+        println!("{load_report:#?}");
     }
 
     Ok(())
 }
 
-pub fn logs_sync(alias_or_index: String, port: u16, tail: bool, json: bool) -> Result<()> {
-    Runtime::new()?.block_on(async { logs(alias_or_index, port, tail, json).await })
+pub fn logs_sync(
+    alias_or_index: String,
+    port: u16,
+    key: Option<String>,
+    tail: bool,
+    json: bool,
+) -> Result<()> {
+    Runtime::new()?.block_on(async { logs(alias_or_index, port, key, tail, json).await })
 }
 
-pub async fn logs(alias_or_index: String, port: u16, tail: bool, json: bool) -> Result<()> {
-    let response = reqwest::Client::new()
+pub async fn logs(
+    alias_or_index: String,
+    port: u16,
+    key: Option<String>,
+    tail: bool,
+    json: bool,
+) -> Result<()> {
+    let response = herder_client(key)?
         .get(format!(
             "http://localhost:{port}/herder/{alias_or_index}/logs?tail={}&json={}",
             tail.as_str(),
@@ -74,12 +100,12 @@ pub async fn logs(alias_or_index: String, port: u16, tail: bool, json: bool) ->
     Ok(())
 }
 
-pub fn unload_sync(alias_or_index: String, port: u16) -> Result<()> {
-    Runtime::new()?.block_on(async { unload(alias_or_index, port).await })
+pub fn unload_sync(alias_or_index: String, port: u16, key: Option<String>) -> Result<()> {
+    Runtime::new()?.block_on(async { unload(alias_or_index, port, key).await })
 }
 
-pub async fn unload(alias_or_index: String, port: u16) -> Result<()> {
-    let response = reqwest::Client::new()
+pub async fn unload(alias_or_index: String, port: u16, key: Option<String>) -> Result<()> {
+    let response = herder_client(key)?
         .delete(format!("http://localhost:{port}/herder/{alias_or_index}"))
         .send()
         .await?
@@ -92,17 +118,17 @@ pub async fn unload(alias_or_index: String, port: u16) -> Result<()> {
     Ok(())
 }
 
-pub fn loaded_sync(port: u16) -> Result<()> {
-    Runtime::new()?.block_on(async { loaded(port).await })
+pub fn loaded_sync(port: u16, key: Option<String>) -> Result<()> {
+    Runtime::new()?.block_on(async { loaded(port, key).await })
 }
 
-pub async fn loaded(port: u16) -> Result<()> {
-    let response = reqwest::Client::new()
+pub async fn loaded(port: u16, key: Option<String>) -> Result<()> {
+    let response = herder_client(key)?
         .get(format!("http://localhost:{port}/herder"))
         .send()
         .await?
         .error_for_status()?
-        .json::<Vec<ModelConfig>>()
+        .json::<Vec<LoadedModelStatus>>()
         .await?;
 
     println!("{response:#?}");
@@ -110,10 +136,91 @@ pub async fn loaded(port: u16) -> Result<()> {
     Ok(())
 }
 
-pub fn config_sync(config_path: &Path, alias_or_index: Option<String>) -> Result<()> {
+pub fn reload_sync(port: u16, key: Option<String>) -> Result<()> {
+    Runtime::new()?.block_on(async { reload(port, key).await })
+}
+
+pub async fn reload(port: u16, key: Option<String>) -> Result<()> {
+    let response = herder_client(key)?
+        .post(format!("http://localhost:{port}/herder/reload"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ReloadReport>()
+        .await?;
+
+    println!("{response:#?}");
+
+    Ok(())
+}
+
+pub fn usage_sync(
+    port: u16,
+    key: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+) -> Result<()> {
+    Runtime::new()?.block_on(async { usage(port, key, since, until).await })
+}
+
+pub async fn usage(
+    port: u16,
+    key: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+) -> Result<()> {
+    let query = [("since", since), ("until", until)]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect::<Vec<_>>();
+
+    let response = herder_client(key)?
+        .get(format!("http://localhost:{port}/herder/usage"))
+        .query(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<UsageReport>()
+        .await?;
+
+    println!("{response:#?}");
+
+    Ok(())
+}
+
+/// Client for the `/herder` API, authenticated as an admin client when a key is given
+fn herder_client(key: Option<String>) -> Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+
+    if let Some(key) = key {
+        let mut value = HeaderValue::try_from(format!("Bearer {key}"))?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    Ok(reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?)
+}
+
+pub fn config_sync(config_path: &Path, alias_or_index: Option<String>, argv: bool) -> Result<()> {
     let config = Config::load(config_path)?;
 
-    if let Some(alias_or_index) = alias_or_index {
+    if argv {
+        if let Some(alias_or_index) = alias_or_index {
+            let model_config = config.get_model_config(alias_or_index)?;
+
+            println!("{}", format_launch_command(&config, model_config)?);
+        } else {
+            for (index, model_config) in config.models.iter().enumerate() {
+                println!(
+                    "[{index}]: Alias '{}' - {}",
+                    model_config.alias(),
+                    format_launch_command(&config, model_config)?
+                );
+            }
+        }
+    } else if let Some(alias_or_index) = alias_or_index {
         let model_config = config.get_model_config(alias_or_index)?;
 
         println!("{model_config:#?}")
@@ -124,6 +231,103 @@ pub fn config_sync(config_path: &Path, alias_or_index: Option<String>) -> Result
     Ok(())
 }
 
+/// Renders the launch command as a shell command line with the API key and environment redacted.
+fn format_launch_command(config: &Config, model_config: &ModelConfig) -> Result<String> {
+    let Some(launch) = launch_command(config, model_config)? else {
+        return Ok("(external, not spawned)".to_string());
+    };
+
+    let mut words = Vec::new();
+
+    if let Some(working_dir) = &launch.working_dir {
+        words.extend([
+            "cd".to_string(),
+            shell_quote(&working_dir.to_string_lossy()),
+            "&&".to_string(),
+        ]);
+    }
+
+    let mut env = launch.env.iter().collect::<Vec<_>>();
+    env.sort();
+
+    words.extend(env.into_iter().map(|(key, _)| format!("{key}=<redacted>")));
+
+    words.push(shell_quote(&launch.program));
+
+    let mut args = launch.args.iter().peekable();
+
+    while let Some(arg) = args.next() {
+        words.push(shell_quote(arg));
+
+        if arg == "--api-key" && args.next().is_some() {
+            words.push("<redacted>".to_string());
+        }
+    }
+
+    Ok(words.join(" "))
+}
+
+fn shell_quote(word: &str) -> String {
+    let is_plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c));
+
+    if is_plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+pub fn check_sync(config_path: &Path) -> Result<()> {
+    let diagnostics = Config::parse(config_path)?.check();
+
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+
+    let error_count = diagnostics.iter().filter(|d| d.is_error()).count();
+
+    if error_count > 0 {
+        bail!("{error_count} error(s) found in {config_path:?}");
+    }
+
+    println!("{config_path:?} is valid");
+
+    Ok(())
+}
+
+pub fn plan_sync(config_path: &Path, alias_or_index: String) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    for (
+        step,
+        LoadPlanStep {
+            index,
+            alias,
+            unloads,
+        },
+    ) in config
+        .get_load_plan(alias_or_index)?
+        .into_iter()
+        .enumerate()
+    {
+        let unloads = if unloads.is_empty() {
+            String::new()
+        } else {
+            format!(" - Unloads: {}", unloads.join(", "))
+        };
+
+        println!(
+            "{}. Load '{alias}' (config.models[{index}]){unloads}",
+            step + 1
+        );
+    }
+
+    Ok(())
+}
+
 pub fn list_sync(config_path: &Path) -> Result<()> {
     let config = Config::load(config_path)?;
 
@@ -132,10 +336,16 @@ pub fn list_sync(config_path: &Path) -> Result<()> {
             model_config.alias(),
             match &model_config.config {
                 ModelTypeConfig::LlamaCpp(x) => format!(
-                    "LlamaCpp ('{}' - http://{}:{})",
-                    x.hf_repo,
+                    "LlamaCpp ({}{} - http://{}:{})",
+                    x.source,
+                    x.hf_file
+                        .as_ref()
+                        .map(|hf_file| format!(" '{hf_file}'"))
+                        .unwrap_or_default(),
                     x.host.as_deref().unwrap_or("localhost"),
                     x.port
+                        .map(|port| port.to_string())
+                        .unwrap_or("auto".to_string())
                 ),
                 ModelTypeConfig::External(x) => {
                     format!(
@@ -144,6 +354,14 @@ pub fn list_sync(config_path: &Path) -> Result<()> {
                         x.unwrap_provider().base_url
                     )
                 }
+                ModelTypeConfig::Command(x) => format!(
+                    "Command ('{}' - http://{}:{})",
+                    x.argv.join(" "),
+                    x.host.as_deref().unwrap_or("localhost"),
+                    x.port
+                        .map(|port| port.to_string())
+                        .unwrap_or("auto".to_string())
+                ),
             },
         );
 
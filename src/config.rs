@@ -1,84 +1,246 @@
 mod alias_or_index;
-mod llama_cpp;
+mod check;
+mod client;
+mod command;
+mod detach;
 mod external;
+mod format;
+mod group;
+mod http_client;
+mod include;
+mod llama_cpp;
 mod model;
-mod detach;
+mod plan;
+mod port_range;
+mod rate_limit;
+mod restart;
+mod secret;
+mod template;
+mod v1_models;
 
 pub use alias_or_index::*;
+pub use check::*;
+pub use client::*;
+pub use command::*;
+pub use detach::*;
+pub use external::*;
+pub use format::*;
+pub use group::*;
+pub use http_client::*;
+pub use include::watched_paths;
 pub use llama_cpp::*;
 pub use model::*;
-pub use external::*;
-pub use detach::*;
+pub use plan::*;
+pub use port_range::*;
+pub use rate_limit::*;
+pub use restart::*;
+pub use secret::*;
+pub use template::{DefaultsConfig, resolve_templates};
+pub use v1_models::*;
 
 use anyhow::{Context, Result, anyhow, bail};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use utils_rs::option::as_bool::AsBool;
-use std::{collections::{HashMap, HashSet}, fs::canonicalize, path::Path};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs::canonicalize,
+    path::{Path, PathBuf},
+};
+use utils_rs::{option::as_bool::AsBool, prelude::ResolveEnvParts};
+
+const DEFAULT_STATE_DIR: &str = "~/.local/state/hrdr";
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
     #[serde(rename = "$schema")]
     pub schema: Option<String>,
+    #[schemars(schema_with = "template::models_schema")]
     pub models: Vec<ModelConfig>,
+    /// Files or globs relative to this file whose `models`, `providers` and `templates` are merged
+    /// into this config. Config files in an `hrdr.d` directory next to this file are always included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    /// File each model was included from by index, `None` for models defined in this file
+    #[serde(skip)]
+    pub model_sources: Vec<Option<PathBuf>>,
+    /// Secrets that couldn't be read when parsing, reported by `check`
+    #[serde(skip)]
+    pub secret_errors: Vec<Diagnostic>,
+    /// Partial model configs that models and other templates can `extend`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "template::templates_schema")]
+    pub templates: Option<HashMap<String, Map<String, Value>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub defaults: Option<DefaultsConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub providers: Option<HashMap<String, ExternalProviderConfig>>,
+    /// Callers allowed to use the router by name. When set, proxied requests and `/v1/models`
+    /// require the bearer key of one of these clients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clients: Option<HashMap<String, ClientConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_defaults_on_launch: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_on_demand: Option<bool>,
+    /// Default idle timeout for all models, see `ModelConfig::idle_timeout_secs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Total cost of models that may be loaded at once. Least recently used models are evicted to make room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<u64>,
+    /// Named groups of models that limit how many members may be loaded at once, in addition to `unloads`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<HashMap<String, GroupConfig>>,
+    /// Default path or name of the llama-server binary. Defaults to `llama-server` on the `PATH`.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub load_defaults_on_launch : Option<bool>,
+    pub llama_cpp_binary: Option<String>,
+    /// Ports assigned to llama-cpp and command models without a `port`. Defaults to 8100-8199.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_range: Option<PortRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v1_models: Option<V1ModelsConfig>,
+    /// Default settings of the HTTP client requests are proxied with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_client: Option<HttpClientConfig>,
+    /// Directory rate limit counters are kept in across restarts. Defaults to `~/.local/state/hrdr`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
     #[serde(default)]
-    pub detach: DetachConfig
+    pub detach: DetachConfig,
 }
 
 impl Config {
+    /// Parses the config and fails if `check` reports any errors.
     pub fn load(path: &Path) -> Result<Config> {
+        let config = Self::parse(path)?;
+
+        let errors = config
+            .check()
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<_>>();
+
+        if !errors.is_empty() {
+            bail!("Invalid config {path:?}:\n{}", errors.join("\n"));
+        }
+
+        Ok(config)
+    }
+
+    /// Reads the config and its includes, resolves templates, secrets, local model paths and
+    /// provider references where possible, without validating it. Secrets that can't be read are
+    /// reported by `check`.
+    pub fn parse(path: &Path) -> Result<Config> {
         let path = canonicalize(path)?;
-        let file_content =
-            std::fs::read_to_string(&path).context("Failed to read config file content")?;
-        let Config { schema, models, providers, load_defaults_on_launch, detach }: Config = serde_json::from_str(&file_content)?;
-
-        let mut defaults = HashSet::new();
-
-        Ok(Config {
-            schema,
-            load_defaults_on_launch,
-            detach,
-            models: models
-                .into_iter()
-                .map(|model_config| {
-                    if model_config.is_default.as_bool() && !defaults.insert(model_config.alias().to_string()) {
-                        bail!("Multiple models with alias '{}' marked as default", model_config.alias())
-                    }
-                    
-                    anyhow::Ok(ModelConfig {
-                        config: match model_config.config {
-                            ModelTypeConfig::External(ExternalConfig::ProviderNameAndModel(
-                                ExternalProviderNameAndModelConfig { provider, model },
-                            )) => {
-                                  ModelTypeConfig::External(ExternalConfig::ProviderAndModel(
-                                    ExternalProviderAndModelConfig {
-                                        provider: providers
-                                            .as_ref()
-                                            .and_then(|providers| providers.get(&provider).cloned())
-                                            .ok_or(anyhow!("External model config {model:?} references missing provider '{provider}'"))?,
-                                        model,
-                                    },
-                                ))          
-                            },
-                            other => other,
-                        },
-                        ..model_config
-                    })
-                })
-               .collect::<Result<Vec<_>, _>>()?,
-            providers
-        })
+        let mut config = include::read_value(&path)?;
+        let sources = include::merge_includes(&path, &mut config)?;
+        resolve_templates(&mut config)?;
+        let mut config: Config = serde_json::from_value(config)?;
+        let config_dir = path.parent().expect("Canonical file path has a parent");
+        let mut secret_errors = Vec::new();
+
+        let mut resolve = |secret: &mut SecretConfig, dir: &Path, path: String| {
+            if let Err(err) = secret.resolve(dir) {
+                secret_errors.push(Diagnostic::error(path, format!("{err:#}")));
+            }
+        };
+
+        for (name, provider) in config.providers.iter_mut().flatten() {
+            resolve(
+                &mut provider.api_key,
+                sources.provider_dir(name, config_dir),
+                format!("providers.{name}.api-key"),
+            );
+        }
+
+        for (name, client) in config.clients.iter_mut().flatten() {
+            resolve(&mut client.key, config_dir, format!("clients.{name}.key"));
+        }
+
+        for (index, model_config) in config.models.iter_mut().enumerate() {
+            let model_dir = sources.model_dir(index, config_dir);
+
+            let api_key = match &mut model_config.config {
+                ModelTypeConfig::LlamaCpp(x) => x.api_key.as_mut(),
+                ModelTypeConfig::Command(x) => x.api_key.as_mut(),
+                ModelTypeConfig::External(ExternalConfig::ProviderAndModel(x)) => {
+                    Some(&mut x.provider.api_key)
+                }
+                ModelTypeConfig::External(ExternalConfig::ProviderNameAndModel(_)) => None,
+            };
+
+            if let Some(api_key) = api_key {
+                resolve(
+                    api_key,
+                    model_dir,
+                    format!("models[{index}].config.api-key"),
+                );
+            }
+
+            let env = match &mut model_config.config {
+                ModelTypeConfig::LlamaCpp(x) => x.env.as_mut(),
+                ModelTypeConfig::Command(x) => x.env.as_mut(),
+                ModelTypeConfig::External(_) => None,
+            };
+
+            for (key, value) in env.into_iter().flatten() {
+                resolve(
+                    value,
+                    model_dir,
+                    format!("models[{index}].config.env.{key}"),
+                );
+            }
+
+            if let ModelTypeConfig::LlamaCpp(LlamaCppModelConfig {
+                source: ModelSource::Model(model_path),
+                ..
+            }) = &mut model_config.config
+                && model_path.is_relative()
+            {
+                *model_path = model_dir.join(&model_path);
+            }
+
+            if let ModelTypeConfig::External(ExternalConfig::ProviderNameAndModel(
+                ExternalProviderNameAndModelConfig { provider, model },
+            )) = &model_config.config
+                && let Some(provider) = config
+                    .providers
+                    .as_ref()
+                    .and_then(|providers| providers.get(provider))
+            {
+                model_config.config = ModelTypeConfig::External(ExternalConfig::ProviderAndModel(
+                    ExternalProviderAndModelConfig {
+                        provider: provider.clone(),
+                        model: model.clone(),
+                    },
+                ));
+            }
+        }
+
+        config.model_sources = sources.models;
+        config.secret_errors = secret_errors;
+
+        Ok(config)
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        match &self.state_dir {
+            Some(state_dir) => state_dir.resolve_env_parts(),
+            None => DEFAULT_STATE_DIR.resolve_env_parts(),
+        }
     }
 
     pub fn get_model_config(
         &self,
         alias_or_index: impl Into<AliasOrIndex>,
     ) -> Result<&ModelConfig> {
+        Ok(&self.models[self.get_model_index(alias_or_index)?])
+    }
+
+    pub fn get_model_index(&self, alias_or_index: impl Into<AliasOrIndex>) -> Result<usize> {
         let alias_or_index = alias_or_index.into();
 
         let mut model_configs = self
@@ -92,7 +254,7 @@ impl Config {
             .collect::<Vec<_>>();
 
         if model_configs.is_empty() {
-            bail!("Model with alias or index {alias_or_index:#?} not found");
+            bail!("Model with {alias_or_index} not found");
         }
 
         let count = model_configs.len();
@@ -104,8 +266,57 @@ impl Config {
             );
         }
 
-        let (_, model_config) = model_configs.remove(0);
+        let (index, _) = model_configs.remove(0);
+
+        Ok(index)
+    }
+
+    pub fn get_on_demand_model_index(&self, alias: &str) -> Result<usize> {
+        let candidates = self
+            .models
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.alias() == alias)
+            .collect::<Vec<_>>();
+
+        match candidates.as_slice() {
+            [] => bail!("Model with alias '{alias}' not found"),
+            [(index, _)] => Ok(*index),
+            _ => candidates
+                .iter()
+                .find(|(_, m)| m.is_default.as_bool())
+                .map(|(index, _)| *index)
+                .ok_or(anyhow!(
+                    "Multiple models with alias '{alias}' exist and none of them is marked as default"
+                )),
+        }
+    }
+
+    /// File the model at `index` was included from, `None` if defined in the main config file
+    pub fn get_model_source(&self, index: usize) -> Option<&Path> {
+        self.model_sources.get(index)?.as_deref()
+    }
+
+    /// Returns the groups `model_config` is a member of along with the aliases of all their members.
+    pub fn get_groups(
+        &self,
+        model_config: &ModelConfig,
+    ) -> Result<Vec<(&str, &GroupConfig, Vec<&str>)>> {
+        let mut groups = Vec::new();
+
+        for (name, group) in self.groups.iter().flatten() {
+            let members = group
+                .members
+                .iter()
+                .map(|alias_or_index| Ok(self.get_model_config(alias_or_index)?.alias()))
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Failed resolving members of group '{name}'"))?;
+
+            if members.contains(&model_config.alias()) {
+                groups.push((name.as_str(), group, members));
+            }
+        }
 
-        Ok(model_config)
+        Ok(groups)
     }
 }
@@ -1,5 +1,7 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum AliasOrIndex {
@@ -22,6 +24,15 @@ impl AliasOrIndex {
     }
 }
 
+impl Display for AliasOrIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AliasOrIndex::Alias(alias) => f.write_fmt(format_args!("alias '{alias}'")),
+            AliasOrIndex::Index(index) => f.write_fmt(format_args!("index {index}")),
+        }
+    }
+}
+
 impl From<&str> for AliasOrIndex {
     fn from(str: &str) -> Self {
         str.to_string().into()
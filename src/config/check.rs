@@ -0,0 +1,598 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+use utils_rs::option::as_bool::AsBool;
+
+use crate::config::{
+    AliasOrIndex, Config, ExternalConfig, LoadsCycle, ModelSource, ModelTypeConfig, PortRange,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+    /// Included file the diagnosed model was defined in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+impl Diagnostic {
+    pub(super) fn error(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+            file: None,
+        }
+    }
+
+    fn warning(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+            file: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        f.write_fmt(format_args!("{severity}: "))?;
+
+        if let Some(file) = &self.file {
+            f.write_fmt(format_args!("{}: ", file.display()))?;
+        }
+
+        f.write_fmt(format_args!("{}: {}", self.path, self.message))
+    }
+}
+
+impl Config {
+    /// Validates the config as a whole, reporting every problem found along with its JSON path.
+    pub fn check(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = self.secret_errors.clone();
+
+        self.check_aliases(&mut diagnostics);
+        self.check_providers(&mut diagnostics);
+        self.check_clients(&mut diagnostics);
+        self.check_sources(&mut diagnostics);
+        self.check_ports(&mut diagnostics);
+        self.check_references(&mut diagnostics);
+        self.check_groups(&mut diagnostics);
+        self.check_concurrency(&mut diagnostics);
+
+        // Load plans can only be resolved once every reference is valid
+        if !diagnostics.iter().any(Diagnostic::is_error) {
+            self.check_load_plans(&mut diagnostics);
+        }
+
+        for diagnostic in &mut diagnostics {
+            diagnostic.file = diagnostic
+                .path
+                .strip_prefix("models[")
+                .and_then(|rest| rest.split_once(']'))
+                .and_then(|(index, _)| index.parse().ok())
+                .and_then(|index| self.get_model_source(index))
+                .map(Path::to_path_buf);
+        }
+
+        diagnostics
+    }
+
+    fn check_aliases(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut first_uses = HashMap::new();
+        let mut first_defaults = HashMap::new();
+
+        for (index, model_config) in self.models.iter().enumerate() {
+            let alias = model_config.alias();
+
+            if let Some(&first_use) = first_uses.get(alias)
+                && self.get_model_source(first_use) != self.get_model_source(index)
+            {
+                diagnostics.push(Diagnostic::error(
+                    format!("models[{index}]"),
+                    format!(
+                        "Alias '{alias}' is also used by models[{first_use}] in {}, aliases must be unique across files",
+                        self.get_model_source(first_use)
+                            .map(|path| format!("{path:?}"))
+                            .unwrap_or("the main config file".to_string())
+                    ),
+                ));
+            } else if let Some(first_use) = first_uses.get(alias) {
+                diagnostics.push(Diagnostic::warning(
+                    format!("models[{index}]"),
+                    format!(
+                        "Alias '{alias}' is also used by models[{first_use}], references to it must use an index"
+                    ),
+                ));
+            } else {
+                first_uses.insert(alias, index);
+            }
+
+            if model_config.is_default.as_bool() {
+                if let Some(first_default) = first_defaults.get(alias) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("models[{index}].default"),
+                        format!(
+                            "Alias '{alias}' is already marked as default by models[{first_default}]"
+                        ),
+                    ));
+                } else {
+                    first_defaults.insert(alias, index);
+                }
+            }
+        }
+    }
+
+    fn check_providers(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (index, model_config) in self.models.iter().enumerate() {
+            if let ModelTypeConfig::External(ExternalConfig::ProviderNameAndModel(x)) =
+                &model_config.config
+            {
+                diagnostics.push(Diagnostic::error(
+                    format!("models[{index}].config.provider"),
+                    format!("References missing provider '{}'", x.provider),
+                ));
+            }
+        }
+    }
+
+    fn check_clients(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut names = self.clients.iter().flatten().collect::<Vec<_>>();
+        let mut first_uses = HashMap::new();
+
+        names.sort_by_key(|(name, _)| name.as_str());
+
+        if !names.is_empty() && !names.iter().any(|(_, client)| client.is_admin()) {
+            diagnostics.push(Diagnostic::warning(
+                "clients",
+                "No client is admin, so the /herder API and the hrdr commands using it are unavailable",
+            ));
+        }
+
+        for (name, client) in names {
+            if let Some(key) = client.key.secret() {
+                if let Some(first_use) = first_uses.get(key.expose_ref()) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("clients.{name}.key"),
+                        format!("Key is also used by client '{first_use}', keys must be unique"),
+                    ));
+                } else {
+                    first_uses.insert(key.expose_ref(), name);
+                }
+            }
+
+            for (index, pattern) in client.models.iter().enumerate() {
+                match glob::Pattern::new(pattern) {
+                    Err(err) => diagnostics.push(Diagnostic::error(
+                        format!("clients.{name}.models[{index}]"),
+                        format!("Invalid glob '{pattern}': {err}"),
+                    )),
+                    Ok(glob)
+                        if !self
+                            .models
+                            .iter()
+                            .any(|model_config| glob.matches(model_config.alias())) =>
+                    {
+                        diagnostics.push(Diagnostic::warning(
+                            format!("clients.{name}.models[{index}]"),
+                            format!("'{pattern}' matches no model alias"),
+                        ))
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+
+    fn check_sources(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (index, model_config) in self.models.iter().enumerate() {
+            if let ModelTypeConfig::Command(x) = &model_config.config
+                && x.argv.is_empty()
+            {
+                diagnostics.push(Diagnostic::error(
+                    format!("models[{index}].config.argv"),
+                    "Must contain at least the program",
+                ));
+            }
+
+            let ModelTypeConfig::LlamaCpp(x) = &model_config.config else {
+                continue;
+            };
+
+            // Any source key besides the one deserialized lands in the passed through arguments
+            for key in ["hf-repo", "model", "model-url"] {
+                if x.additional_properties.contains_key(key) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("models[{index}].config.{key}"),
+                        format!(
+                            "Only one of hf-repo, model and model-url may be set, but the model is \
+                             also loaded from {}",
+                            x.source
+                        ),
+                    ));
+                }
+            }
+
+            if x.hf_file.is_some() && !matches!(x.source, ModelSource::HfRepo(_)) {
+                diagnostics.push(Diagnostic::error(
+                    format!("models[{index}].config.hf-file"),
+                    format!(
+                        "Only applies to hf-repo, but the model is loaded from {}",
+                        x.source
+                    ),
+                ));
+            }
+
+            if let ModelSource::Model(path) = &x.source
+                && !path.is_file()
+            {
+                diagnostics.push(Diagnostic::warning(
+                    format!("models[{index}].config.model"),
+                    format!("File {path:?} does not exist"),
+                ));
+            }
+        }
+    }
+
+    fn check_ports(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if let Some(PortRange { start, end }) = &self.port_range
+            && start > end
+        {
+            diagnostics.push(Diagnostic::error(
+                "port-range",
+                format!("Start {start} is after end {end}"),
+            ));
+        }
+
+        let mut first_uses = HashMap::new();
+
+        for (index, model_config) in self.models.iter().enumerate() {
+            let Some(port) = model_config.config.port() else {
+                continue;
+            };
+
+            if let Some(first_use) = first_uses.get(&port) {
+                diagnostics.push(Diagnostic::error(
+                    format!("models[{index}].config.port"),
+                    format!("Port {port} is also used by models[{first_use}]"),
+                ));
+            } else {
+                first_uses.insert(port, index);
+            }
+        }
+    }
+
+    fn check_references(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut check = |path: String, alias_or_index: &AliasOrIndex| {
+            if let Err(err) = self.get_model_index(alias_or_index) {
+                diagnostics.push(Diagnostic::error(path, err.to_string()));
+            }
+        };
+
+        for (index, model_config) in self.models.iter().enumerate() {
+            for (i, alias_or_index) in model_config.loads.iter().flatten().enumerate() {
+                check(format!("models[{index}].loads[{i}]"), alias_or_index);
+            }
+
+            for (i, alias_or_index) in model_config.unloads.iter().flatten().enumerate() {
+                check(format!("models[{index}].unloads[{i}]"), alias_or_index);
+            }
+        }
+
+        for (name, group) in self.groups.iter().flatten() {
+            for (i, alias_or_index) in group.members.iter().enumerate() {
+                check(format!("groups.{name}.members[{i}]"), alias_or_index);
+            }
+        }
+    }
+
+    fn check_groups(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (name, group) in self.groups.iter().flatten() {
+            if group.max_loaded == Some(0) {
+                diagnostics.push(Diagnostic::error(
+                    format!("groups.{name}.max-loaded"),
+                    "Must be at least 1, otherwise no member can ever be loaded",
+                ));
+            }
+        }
+    }
+
+    fn check_concurrency(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (index, model_config) in self.models.iter().enumerate() {
+            if model_config.max_concurrent == Some(0) {
+                diagnostics.push(Diagnostic::error(
+                    format!("models[{index}].max-concurrent"),
+                    "Must be at least 1, otherwise every request waits forever",
+                ));
+            }
+        }
+    }
+
+    /// Reports cycles in `loads` and models unloading what they load along with them
+    fn check_load_plans(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for index in 0..self.models.len() {
+            let Err(err) = self.get_load_plan(index) else {
+                continue;
+            };
+
+            match err.downcast_ref::<LoadsCycle>() {
+                // Every member of a cycle finds it, so it's only reported for the first one
+                Some(cycle) if cycle.indices.iter().min() != Some(&index) => {}
+                _ => diagnostics.push(Diagnostic::error(
+                    format!("models[{index}].loads"),
+                    err.to_string(),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn command(alias: &str) -> Value {
+        json!({ "type": "command", "config": { "alias": alias, "argv": ["server"] } })
+    }
+
+    fn llama_cpp(config: Value) -> Value {
+        json!({ "type": "llama-cpp", "config": config })
+    }
+
+    fn config(config: Value) -> Config {
+        serde_json::from_value(config).unwrap()
+    }
+
+    /// Severity and path of every diagnostic
+    fn check(config: &Config) -> Vec<String> {
+        config
+            .check()
+            .into_iter()
+            .map(|diagnostic| format!("{:?} {}", diagnostic.severity, diagnostic.path))
+            .collect()
+    }
+
+    fn check_models(models: Vec<Value>) -> Vec<String> {
+        check(&config(json!({ "models": models })))
+    }
+
+    #[test]
+    fn valid_configs_have_no_diagnostics() {
+        let config = config(json!({
+            "port-range": { "start": 9000, "end": 9010 },
+            "clients": { "admin": { "key": "key", "models": ["*"], "admin": true } },
+            "groups": { "chat": { "members": ["a", 1], "max-loaded": 2 } },
+            "models": [
+                {
+                    "type": "command",
+                    "loads": ["b"],
+                    "max-concurrent": 1,
+                    "config": { "alias": "a", "argv": ["server"], "port": 9100 }
+                },
+                {
+                    "type": "command",
+                    "unloads": ["c"],
+                    "config": { "alias": "b", "argv": ["server"], "port": 9101 }
+                },
+                llama_cpp(json!({ "alias": "c", "hf-repo": "org/repo", "hf-file": "m.gguf" })),
+                {
+                    "type": "external",
+                    "config": { "base-url": "http://localhost:1", "api-key": "key", "id": "d" }
+                }
+            ]
+        }));
+
+        assert!(check(&config).is_empty(), "{:?}", check(&config));
+    }
+
+    #[test]
+    fn aliases_may_repeat_within_a_file_but_not_across_files() {
+        assert_eq!(
+            check_models(vec![command("a"), command("a")]),
+            ["Warning models[1]"]
+        );
+
+        let mut config = config(json!({ "models": [command("a"), command("a")] }));
+        config.model_sources = vec![None, Some(PathBuf::from("/etc/hrdr.d/a.json"))];
+
+        assert_eq!(check(&config), ["Error models[1]"]);
+    }
+
+    #[test]
+    fn each_alias_has_at_most_one_default() {
+        let mut a = command("a");
+        a["default"] = json!(true);
+
+        assert_eq!(
+            check_models(vec![a.clone(), command("b")]),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            check_models(vec![a.clone(), a]),
+            ["Warning models[1]", "Error models[1].default"]
+        );
+    }
+
+    #[test]
+    fn providers_must_exist() {
+        let external = |config: Value| json!({ "type": "external", "config": config });
+
+        assert!(
+            check_models(vec![external(json!({
+                "base-url": "http://localhost:1",
+                "api-key": "key",
+                "id": "m"
+            }))])
+            .is_empty()
+        );
+        assert_eq!(
+            check_models(vec![external(json!({ "provider": "missing", "id": "m" }))]),
+            ["Error models[0].config.provider"]
+        );
+    }
+
+    #[test]
+    fn clients_need_an_admin_unique_keys_and_valid_globs() {
+        let check_clients = |clients: Value| {
+            check(&config(
+                json!({ "clients": clients, "models": [command("a")] }),
+            ))
+        };
+
+        assert!(
+            check_clients(json!({ "a": { "key": "a", "models": ["a"], "admin": true } }))
+                .is_empty()
+        );
+        assert_eq!(
+            check_clients(json!({ "a": { "key": "a", "models": ["*"] } })),
+            ["Warning clients"]
+        );
+        assert_eq!(
+            check_clients(json!({
+                "a": { "key": "key", "models": ["*"], "admin": true },
+                "b": { "key": "key", "models": ["[", "b*"] }
+            })),
+            [
+                "Error clients.b.key",
+                "Error clients.b.models[0]",
+                "Warning clients.b.models[1]"
+            ]
+        );
+    }
+
+    #[test]
+    fn models_have_exactly_one_source() {
+        assert_eq!(
+            check_models(vec![json!({
+                "type": "command",
+                "config": { "alias": "a", "argv": [] }
+            })]),
+            ["Error models[0].config.argv"]
+        );
+        assert_eq!(
+            check_models(vec![llama_cpp(
+                json!({ "alias": "a", "hf-repo": "org/repo", "model-url": "http://localhost/m.gguf" })
+            )])
+            .len(),
+            1
+        );
+        assert_eq!(
+            check_models(vec![llama_cpp(
+                json!({ "alias": "a", "model-url": "http://localhost/m.gguf", "hf-file": "m.gguf" })
+            )]),
+            ["Error models[0].config.hf-file"]
+        );
+        assert_eq!(
+            check_models(vec![llama_cpp(
+                json!({ "alias": "a", "model": "/nonexistent/m.gguf" })
+            )]),
+            ["Warning models[0].config.model"]
+        );
+    }
+
+    #[test]
+    fn ports_must_be_unique_and_ranges_ordered() {
+        let model = |alias: &str, port: u16| json!({ "type": "command", "config": { "alias": alias, "argv": ["server"], "port": port } });
+
+        assert!(check_models(vec![model("a", 9000), model("b", 9001)]).is_empty());
+        assert_eq!(
+            check_models(vec![model("a", 9000), model("b", 9000)]),
+            ["Error models[1].config.port"]
+        );
+        assert_eq!(
+            check(&config(
+                json!({ "port-range": { "start": 9010, "end": 9000 }, "models": [] })
+            )),
+            ["Error port-range"]
+        );
+    }
+
+    #[test]
+    fn references_must_resolve() {
+        let mut a = command("a");
+        a["loads"] = json!(["b", 5]);
+        a["unloads"] = json!(["c"]);
+
+        assert_eq!(
+            check(&config(json!({
+                "groups": { "g": { "members": ["a", "d"] } },
+                "models": [a, command("b")]
+            }))),
+            [
+                "Error models[0].loads[1]",
+                "Error models[0].unloads[0]",
+                "Error groups.g.members[1]"
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_allow_at_least_one_loaded_member() {
+        let check_group = |max_loaded: usize| {
+            check(&config(json!({
+                "groups": { "g": { "members": ["a"], "max-loaded": max_loaded } },
+                "models": [command("a")]
+            })))
+        };
+
+        assert!(check_group(1).is_empty());
+        assert_eq!(check_group(0), ["Error groups.g.max-loaded"]);
+    }
+
+    #[test]
+    fn models_allow_at_least_one_concurrent_request() {
+        let mut a = command("a");
+
+        a["max-concurrent"] = json!(1);
+        assert!(check_models(vec![a.clone()]).is_empty());
+
+        a["max-concurrent"] = json!(0);
+        assert_eq!(check_models(vec![a]), ["Error models[0].max-concurrent"]);
+    }
+
+    #[test]
+    fn load_plans_must_be_acyclic_and_consistent() {
+        let mut a = command("a");
+        let mut b = command("b");
+
+        a["loads"] = json!(["b"]);
+        b["loads"] = json!(["a"]);
+
+        // Reported once per cycle
+        assert_eq!(
+            check_models(vec![a.clone(), b, command("c")]),
+            ["Error models[0].loads"]
+        );
+
+        a["unloads"] = json!(["b"]);
+
+        assert_eq!(
+            check_models(vec![a, command("b")]),
+            ["Error models[0].loads"]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use glob::Pattern;
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::config::{RateLimitConfig, SecretConfig};
+
+/// A caller of the router, identified by the bearer key it sends in `Authorization`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClientConfig {
+    pub key: SecretConfig,
+    /// Aliases the client may use, globs like `qwen-*` or `*` are supported
+    pub models: Vec<String>,
+    /// Source IP ranges the client may connect from, e.g. `10.0.0.0/8` or `::1/128`. Any address by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<Vec<String>>")]
+    pub ips: Option<Vec<IpNet>>,
+    /// Limits on the requests of this client across all models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<RateLimitConfig>,
+    /// May use the `/herder` management API, e.g. with `hrdr reload --key <key>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin: Option<bool>,
+}
+
+impl ClientConfig {
+    pub fn allows_model(&self, alias: &str) -> bool {
+        self.models
+            .iter()
+            .any(|pattern| Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(alias)))
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin.unwrap_or(false)
+    }
+
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        self.ips
+            .as_ref()
+            .is_none_or(|ips| ips.iter().any(|range| range.contains(&ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn client(models: &[&str], ips: Option<&[&str]>) -> ClientConfig {
+        serde_json::from_value(json!({ "key": "key", "models": models, "ips": ips })).unwrap()
+    }
+
+    #[test]
+    fn models_match_aliases_and_globs() {
+        let client = client(&["embed", "qwen-*"], None);
+
+        assert!(client.allows_model("embed"));
+        assert!(client.allows_model("qwen-7b"));
+        assert!(!client.allows_model("llama"));
+        assert!(!client.allows_model("embed-large"));
+    }
+
+    #[test]
+    fn no_models_allows_none() {
+        assert!(!client(&[], None).allows_model("embed"));
+    }
+
+    #[test]
+    fn any_ip_is_allowed_by_default() {
+        let client = client(&["*"], None);
+
+        assert!(client.allows_ip("203.0.113.7".parse().unwrap()));
+        assert!(client.allows_ip("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ips_must_be_in_a_range() {
+        let client = client(&["*"], Some(&["10.0.0.0/8", "::1/128"]));
+
+        assert!(client.allows_ip("10.1.2.3".parse().unwrap()));
+        assert!(client.allows_ip("::1".parse().unwrap()));
+        assert!(!client.allows_ip("192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        let client = client(&["*"], Some(&["10.0.0.0/8"]));
+
+        assert!(client.allows_ip("::ffff:10.1.2.3".parse().unwrap()));
+    }
+}
@@ -0,0 +1,44 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::config::{SecretConfig, render_port};
+
+/// Any OpenAI-compatible server started from a command line, e.g. whisper.cpp or vLLM.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CommandModelConfig {
+    pub alias: String,
+    /// Program followed by its arguments. `{port}`, `{host}` and `{alias}` are replaced in every
+    /// element, e.g. `["whisper-server", "--port", "{port}"]`.
+    pub argv: Vec<String>,
+    /// Assigned from the config-wide `port-range` when the model is loaded if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Environment variables added to the inherited environment. Placeholders are replaced as in
+    /// `argv`. Values are secrets, redacted when the config is shown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, SecretConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<PathBuf>,
+    /// Path or absolute URL polled until the server responds successfully. Defaults to `/health`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_url: Option<String>,
+    /// Model id the server expects in requests. Defaults to `alias`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<SecretConfig>,
+}
+
+impl CommandModelConfig {
+    /// Replaces `{port}`, `{host}` and `{alias}` in `template`.
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{port}", &render_port(self.port))
+            .replace("{host}", self.host.as_deref().unwrap_or("localhost"))
+            .replace("{alias}", &self.alias)
+    }
+}
@@ -1,13 +1,16 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use url::Url;
-use utils_rs::secret::Secret;
+
+use crate::config::{HttpClientConfig, SecretConfig};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct ExternalProviderConfig {
     pub base_url: Url,
-    pub api_key: Secret<String>,
+    pub api_key: SecretConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_client: Option<HttpClientConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
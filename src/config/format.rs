@@ -0,0 +1,49 @@
+use anyhow::{Result, bail};
+use serde_json::Value;
+use std::path::Path;
+
+/// File names looked for when no config path is given, in order of preference
+pub const CONFIG_FILE_NAMES: [&str; 5] = [
+    "hrdr.json",
+    "hrdr.json5",
+    "hrdr.yaml",
+    "hrdr.yml",
+    "hrdr.toml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+
+        Ok(match extension {
+            "json" => ConfigFormat::Json,
+            "json5" => ConfigFormat::Json5,
+            "yaml" | "yml" => ConfigFormat::Yaml,
+            "toml" => ConfigFormat::Toml,
+            _ => bail!(
+                "Unknown config format for {path:?}, expected a .json, .json5, .yaml, .yml or .toml file"
+            ),
+        })
+    }
+
+    /// Parses into a JSON value so templates can be resolved the same way for every format.
+    pub fn parse(&self, content: &str) -> Result<Value> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(content)?,
+            ConfigFormat::Json5 => json5::from_str(content)?,
+            ConfigFormat::Yaml => serde_yaml_ng::from_str(content)?,
+            ConfigFormat::Toml => toml::from_str(content)?,
+        })
+    }
+}
@@ -0,0 +1,19 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AliasOrIndex;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GroupConfig {
+    pub members: Vec<AliasOrIndex>,
+    /// Maximum number of members loaded at once. Defaults to 1, making the members mutually exclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_loaded: Option<usize>,
+}
+
+impl GroupConfig {
+    pub fn max_loaded(&self) -> usize {
+        self.max_loaded.unwrap_or(1)
+    }
+}
@@ -0,0 +1,47 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Settings of the HTTP client requests are proxied with. Unset fields fall back to the
+/// provider's, then the config-wide settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HttpClientConfig {
+    /// Maximum number of idle connections kept open per host. Unlimited by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds idle connections are kept open. Defaults to 90.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_idle_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds to wait for the response headers. Streamed response bodies may take longer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_byte_timeout_secs: Option<u64>,
+    /// Seconds the whole request may take, including streaming the response body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+impl HttpClientConfig {
+    /// Fills unset fields from `fallback`.
+    pub fn or(&self, fallback: &HttpClientConfig) -> HttpClientConfig {
+        HttpClientConfig {
+            pool_max_idle_per_host: self
+                .pool_max_idle_per_host
+                .or(fallback.pool_max_idle_per_host),
+            pool_idle_timeout_secs: self
+                .pool_idle_timeout_secs
+                .or(fallback.pool_idle_timeout_secs),
+            connect_timeout_secs: self.connect_timeout_secs.or(fallback.connect_timeout_secs),
+            first_byte_timeout_secs: self
+                .first_byte_timeout_secs
+                .or(fallback.first_byte_timeout_secs),
+            timeout_secs: self.timeout_secs.or(fallback.timeout_secs),
+        }
+    }
+
+    pub fn first_byte_timeout(&self) -> Option<Duration> {
+        self.first_byte_timeout_secs.map(Duration::from_secs)
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::config::ConfigFormat;
+
+/// Directory next to the main config file whose config files are always included
+const INCLUDE_DIR: &str = "hrdr.d";
+
+/// Top level keys an included file may contain
+const INCLUDABLE_KEYS: [&str; 4] = ["$schema", "models", "providers", "templates"];
+
+pub(super) fn read_value(path: &Path) -> Result<Value> {
+    let file_content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {path:?}"))?;
+
+    ConfigFormat::from_path(path)?
+        .parse(&file_content)
+        .with_context(|| format!("Failed to parse {path:?}"))
+}
+
+/// Returns the files included by the main config at `path`, in the order they are merged:
+/// `include` entries first, then the `hrdr.d` directory sorted by file name.
+pub(super) fn included_paths(path: &Path, config: &Value) -> Result<Vec<PathBuf>> {
+    let config_dir = path.parent().expect("Canonical file path has a parent");
+    let mut paths = Vec::new();
+
+    for (index, pattern) in config
+        .get("include")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        let Some(pattern) = pattern.as_str() else {
+            bail!("include[{index}]: Expected a file path or glob, found {pattern}");
+        };
+
+        let pattern = config_dir.join(pattern);
+        let mut matched = glob::glob(&pattern.to_string_lossy())
+            .with_context(|| format!("include[{index}]: Invalid glob {pattern:?}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("include[{index}]: Failed to expand {pattern:?}"))?;
+
+        if matched.is_empty() {
+            bail!("include[{index}]: No files match {pattern:?}");
+        }
+
+        matched.sort();
+        paths.extend(matched);
+    }
+
+    let include_dir = config_dir.join(INCLUDE_DIR);
+
+    if include_dir.is_dir() {
+        let mut entries = std::fs::read_dir(&include_dir)
+            .with_context(|| format!("Failed to read {include_dir:?}"))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|path| path.is_file() && ConfigFormat::from_path(path).is_ok())
+            .collect::<Vec<_>>();
+
+        entries.sort();
+        paths.extend(entries);
+    }
+
+    let mut unique = Vec::new();
+
+    for path in paths {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve included file {path:?}"))?;
+
+        if !unique.contains(&path) {
+            unique.push(path);
+        }
+    }
+
+    Ok(unique)
+}
+
+/// Files the models and providers of a config were included from
+#[derive(Debug, Default)]
+pub(super) struct IncludeSources {
+    /// By model index, `None` for models defined in the main file
+    pub models: Vec<Option<PathBuf>>,
+    /// By provider name, only for providers defined in an included file
+    pub providers: HashMap<String, PathBuf>,
+}
+
+impl IncludeSources {
+    /// Directory the relative paths of the model at `index` are resolved against
+    pub fn model_dir<'a>(&'a self, index: usize, config_dir: &'a Path) -> &'a Path {
+        dir_of(
+            self.models.get(index).and_then(Option::as_deref),
+            config_dir,
+        )
+    }
+
+    /// Directory the relative paths of the provider `name` are resolved against
+    pub fn provider_dir<'a>(&'a self, name: &str, config_dir: &'a Path) -> &'a Path {
+        dir_of(self.providers.get(name).map(PathBuf::as_path), config_dir)
+    }
+}
+
+fn dir_of<'a>(source: Option<&'a Path>, config_dir: &'a Path) -> &'a Path {
+    source.map_or(config_dir, |source| {
+        source.parent().expect("Canonical file path has a parent")
+    })
+}
+
+/// Merges the models, providers and templates of every included file into `config`.
+///
+/// Returns the files the models and providers were included from. Provider and template names
+/// must be unique across files.
+pub(super) fn merge_includes(path: &Path, config: &mut Value) -> Result<IncludeSources> {
+    let included_paths = included_paths(path, config)?;
+
+    let Some(root) = config.as_object_mut() else {
+        bail!("Expected the config in {path:?} to be an object");
+    };
+
+    let model_count = root
+        .get("models")
+        .and_then(Value::as_array)
+        .map(Vec::len)
+        .unwrap_or_default();
+
+    let mut sources = IncludeSources {
+        models: vec![None; model_count],
+        ..Default::default()
+    };
+    let mut definitions = HashMap::<(&str, String), PathBuf>::new();
+
+    for key in ["providers", "templates"] {
+        for name in root
+            .get(key)
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(name, _)| name)
+        {
+            definitions.insert((key, name.clone()), path.to_path_buf());
+        }
+    }
+
+    for included_path in included_paths {
+        if included_path == path {
+            continue;
+        }
+
+        let Value::Object(included) = read_value(&included_path)? else {
+            bail!("Expected the config in {included_path:?} to be an object");
+        };
+
+        for (key, value) in included {
+            let Some(key) = INCLUDABLE_KEYS
+                .into_iter()
+                .find(|includable| *includable == key)
+            else {
+                bail!(
+                    "{included_path:?}: Only {} can be included, found '{key}'",
+                    INCLUDABLE_KEYS[1..].join(", ")
+                );
+            };
+
+            match (key, value) {
+                ("$schema", _) => {}
+                ("models", Value::Array(models)) => {
+                    sources
+                        .models
+                        .extend(vec![Some(included_path.clone()); models.len()]);
+
+                    root.entry("models")
+                        .or_insert(Value::Array(Vec::new()))
+                        .as_array_mut()
+                        .with_context(|| format!("Expected models in {path:?} to be an array"))?
+                        .extend(models);
+                }
+                (key @ ("providers" | "templates"), Value::Object(entries)) => {
+                    let target = root
+                        .entry(key)
+                        .or_insert(Value::Object(Map::new()))
+                        .as_object_mut()
+                        .with_context(|| format!("Expected {key} in {path:?} to be an object"))?;
+
+                    for (name, entry) in entries {
+                        if let Some(defined_in) = definitions.get(&(key, name.clone())) {
+                            bail!(
+                                "{included_path:?}: {} '{name}' is already defined in {defined_in:?}",
+                                if key == "providers" {
+                                    "Provider"
+                                } else {
+                                    "Template"
+                                }
+                            );
+                        }
+
+                        definitions.insert((key, name.clone()), included_path.clone());
+
+                        if key == "providers" {
+                            sources
+                                .providers
+                                .insert(name.clone(), included_path.clone());
+                        }
+
+                        target.insert(name, entry);
+                    }
+                }
+                (key, _) => bail!("{included_path:?}: Unexpected value for '{key}'"),
+            }
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Paths whose modification means the config at `path` has to be reloaded: the main file, the
+/// `hrdr.d` directory and every included file.
+pub fn watched_paths(path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![path.to_path_buf()];
+
+    let Ok(path) = path.canonicalize() else {
+        return paths;
+    };
+
+    let include_dir = path
+        .parent()
+        .expect("Canonical file path has a parent")
+        .join(INCLUDE_DIR);
+
+    if include_dir.is_dir() {
+        paths.push(include_dir);
+    }
+
+    if let Ok(config) = read_value(&path)
+        && let Ok(included_paths) = included_paths(&path, &config)
+    {
+        paths.extend(included_paths);
+    }
+
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Writes the files into a fresh temporary directory and returns the canonical path of the
+    /// main config `hrdr.json`
+    fn write_config(name: &str, files: &[(&str, Value)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hrdr-include-{name}-{}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(INCLUDE_DIR)).unwrap();
+
+        for (file, content) in files {
+            let file = dir.join(file);
+
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, content.to_string()).unwrap();
+        }
+
+        dir.join("hrdr.json").canonicalize().unwrap()
+    }
+
+    fn merge(path: &Path) -> Result<(Value, IncludeSources)> {
+        let mut config = read_value(path)?;
+        let sources = merge_includes(path, &mut config)?;
+
+        Ok((config, sources))
+    }
+
+    #[test]
+    fn included_models_follow_the_main_file() {
+        let path = write_config(
+            "models",
+            &[
+                (
+                    "hrdr.json",
+                    json!({ "include": ["extra/*.json"], "models": [{ "alias": "main" }] }),
+                ),
+                ("hrdr.d/b.json", json!({ "models": [{ "alias": "dir" }] })),
+                (
+                    "extra/a.json",
+                    json!({ "models": [{ "alias": "included" }] }),
+                ),
+            ],
+        );
+
+        let (config, sources) = merge(&path).unwrap();
+        let config_dir = path.parent().unwrap();
+
+        assert_eq!(
+            config["models"],
+            json!([{ "alias": "main" }, { "alias": "included" }, { "alias": "dir" }])
+        );
+        assert_eq!(sources.model_dir(0, config_dir), config_dir);
+        assert_eq!(sources.model_dir(1, config_dir), config_dir.join("extra"));
+        assert_eq!(
+            sources.model_dir(2, config_dir),
+            config_dir.join(INCLUDE_DIR)
+        );
+
+        std::fs::remove_dir_all(config_dir).unwrap();
+    }
+
+    #[test]
+    fn files_matched_twice_are_included_once() {
+        let path = write_config(
+            "unique",
+            &[
+                (
+                    "hrdr.json",
+                    json!({ "include": ["hrdr.d/*.json", "hrdr.json"] }),
+                ),
+                (
+                    "hrdr.d/a.json",
+                    json!({ "providers": { "p": { "base-url": "http://localhost" } } }),
+                ),
+            ],
+        );
+
+        let (config, sources) = merge(&path).unwrap();
+        let config_dir = path.parent().unwrap();
+
+        assert_eq!(config["providers"].as_object().unwrap().len(), 1);
+        assert_eq!(
+            sources.provider_dir("p", config_dir),
+            config_dir.join(INCLUDE_DIR)
+        );
+
+        std::fs::remove_dir_all(config_dir).unwrap();
+    }
+
+    #[test]
+    fn names_must_be_unique_across_files() {
+        let path = write_config(
+            "duplicates",
+            &[
+                ("hrdr.json", json!({ "templates": { "base": {} } })),
+                ("hrdr.d/a.json", json!({ "templates": { "base": {} } })),
+            ],
+        );
+
+        let error = merge(&path).unwrap_err().to_string();
+
+        assert!(
+            error.contains("Template 'base' is already defined"),
+            "{error}"
+        );
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn only_includable_keys_are_accepted() {
+        let path = write_config(
+            "keys",
+            &[
+                ("hrdr.json", json!({})),
+                ("hrdr.d/a.json", json!({ "clients": {} })),
+            ],
+        );
+
+        let error = merge(&path).unwrap_err().to_string();
+
+        assert!(error.contains("found 'clients'"), "{error}");
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn unmatched_includes_are_rejected() {
+        let path = write_config(
+            "unmatched",
+            &[("hrdr.json", json!({ "include": ["missing/*.json"] }))],
+        );
+
+        assert!(merge(&path).is_err());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
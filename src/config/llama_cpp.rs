@@ -1,18 +1,63 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use utils_rs::secret::Secret;
+use std::{collections::HashMap, fmt::Display, path::PathBuf};
+use url::Url;
+
+use crate::config::SecretConfig;
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub struct LlamaCppModelConfig {
-    pub hf_repo: String,
-    pub port: u16,
+    #[serde(flatten)]
+    pub source: ModelSource,
+    /// File within `hf-repo`, e.g. a specific quantization
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hf_file: Option<String>,
+    /// Assigned from the config-wide `port-range` when the model is loaded if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
     pub alias: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub api_key: Option<Secret<String>>,
+    pub api_key: Option<SecretConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub host: Option<String>,
+    /// Path or name of the llama-server binary. Overrides the config-wide `llama-cpp-binary`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+    /// Environment variables added to the inherited environment, e.g. `HF_TOKEN` or `LLAMA_CACHE`.
+    /// Values are secrets, redacted when the config is shown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, SecretConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<PathBuf>,
+    /// Passed to llama-server verbatim after all other arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_args: Option<Vec<String>>,
+    /// Render additional properties set to `false` as `--no-<key>` instead of omitting them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negate_false_flags: Option<bool>,
     #[serde(flatten)]
     pub additional_properties: Map<String, Value>,
 }
+
+/// Where llama-server loads the model from. Exactly one of the keys must be set.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModelSource {
+    HfRepo(String),
+    /// Local GGUF file, relative paths are resolved against the directory of the config file
+    /// declaring the model
+    Model(PathBuf),
+    ModelUrl(Url),
+}
+
+impl Display for ModelSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelSource::HfRepo(hf_repo) => f.write_fmt(format_args!("hf-repo '{hf_repo}'")),
+            ModelSource::Model(path) => f.write_fmt(format_args!("model {path:?}")),
+            ModelSource::ModelUrl(url) => f.write_fmt(format_args!("model-url '{url}'")),
+        }
+    }
+}
@@ -1,11 +1,13 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use url::Url;
 use utils_rs::secret::Secret;
 
 use crate::config::{
+    CommandModelConfig, HttpClientConfig, RateLimitConfig, SecretConfig,
     alias_or_index::AliasOrIndex, external::ExternalConfig, llama_cpp::LlamaCppModelConfig,
+    restart::RestartPolicy,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -13,17 +15,79 @@ use crate::config::{
 pub enum ModelTypeConfig {
     LlamaCpp(LlamaCppModelConfig),
     External(ExternalConfig),
+    Command(CommandModelConfig),
+}
+
+impl ModelTypeConfig {
+    /// Port of the spawned server, `None` for external models
+    pub fn port_mut(&mut self) -> Option<&mut Option<u16>> {
+        match self {
+            ModelTypeConfig::LlamaCpp(x) => Some(&mut x.port),
+            ModelTypeConfig::Command(x) => Some(&mut x.port),
+            ModelTypeConfig::External(_) => None,
+        }
+    }
+
+    /// Host the spawned server listens on
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            ModelTypeConfig::LlamaCpp(x) => Some(x.host.as_deref().unwrap_or("localhost")),
+            ModelTypeConfig::Command(x) => Some(x.host.as_deref().unwrap_or("localhost")),
+            ModelTypeConfig::External(_) => None,
+        }
+    }
+
+    /// Port configured for the spawned server
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ModelTypeConfig::LlamaCpp(x) => x.port,
+            ModelTypeConfig::Command(x) => x.port,
+            ModelTypeConfig::External(_) => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct ModelConfig {
+    /// Name of the template this config is deep-merged on top of, see `Config::templates`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub unloads: Option<Vec<AliasOrIndex>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub loads: Option<Vec<AliasOrIndex>>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "default")]
     pub is_default: Option<bool>,
+    /// Unload the model after this many seconds without proxied requests. Overrides the config-wide `idle-timeout-secs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Seconds to wait for a spawned model to report healthy before the load fails. Defaults to 600.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ready_timeout_secs: Option<u64>,
+    /// Seconds to wait for a spawned model to exit after SIGTERM before it is killed. Defaults to 10.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_timeout_secs: Option<u64>,
+    /// Applies once the model has been ready, a model that fails to start is never restarted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartPolicy>,
+    /// Resource cost counted against the config-wide `budget`, e.g. memory in MB or a number of slots
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<u64>,
+    /// Pinned models are never evicted to make room for other models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned: Option<bool>,
+    /// Maximum number of requests proxied to the model at once, further requests are queued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of requests waiting for `max-concurrent`, further requests are rejected with 429
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_queue: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_client: Option<HttpClientConfig>,
+    /// Limits on the requests to this alias across all clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<RateLimitConfig>,
     #[serde(flatten)]
     pub config: ModelTypeConfig,
 }
@@ -33,6 +97,7 @@ impl ModelConfig {
         match &self.config {
             ModelTypeConfig::LlamaCpp(x) => &x.alias,
             ModelTypeConfig::External(x) => x.model().alias.as_deref().unwrap_or(&x.model().id),
+            ModelTypeConfig::Command(x) => &x.alias,
         }
     }
 
@@ -40,25 +105,40 @@ impl ModelConfig {
         match &self.config {
             ModelTypeConfig::LlamaCpp(x) => &x.alias,
             ModelTypeConfig::External(x) => &x.model().id,
+            ModelTypeConfig::Command(x) => x.id.as_deref().unwrap_or(&x.alias),
         }
     }
 
     pub fn api_key(&self) -> Option<&Secret<String>> {
         match &self.config {
-            ModelTypeConfig::LlamaCpp(x) => x.api_key.as_ref(),
-            ModelTypeConfig::External(x) => Some(&x.unwrap_provider().api_key),
+            ModelTypeConfig::LlamaCpp(x) => x.api_key.as_ref().and_then(SecretConfig::secret),
+            ModelTypeConfig::External(x) => x.unwrap_provider().api_key.secret(),
+            ModelTypeConfig::Command(x) => x.api_key.as_ref().and_then(SecretConfig::secret),
         }
     }
 
-    pub fn url(&self) -> Result<Url, url::ParseError> {
+    /// Fails for spawned models whose `auto` port hasn't been assigned by loading them yet.
+    pub fn url(&self) -> Result<Url> {
         match &self.config {
-            ModelTypeConfig::LlamaCpp(x) => format!(
+            ModelTypeConfig::LlamaCpp(x) => Ok(format!(
                 "http://{host}:{port}",
                 host = x.host.as_deref().unwrap_or("localhost"),
-                port = x.port
+                port = x.port.ok_or(anyhow!(
+                    "Model '{}' has no port until it is loaded",
+                    x.alias
+                ))?
             )
-            .parse(),
+            .parse()?),
             ModelTypeConfig::External(x) => Ok(x.unwrap_provider().base_url.clone()),
+            ModelTypeConfig::Command(x) => Ok(format!(
+                "http://{host}:{port}",
+                host = x.host.as_deref().unwrap_or("localhost"),
+                port = x.port.ok_or(anyhow!(
+                    "Model '{}' has no port until it is loaded",
+                    x.alias
+                ))?
+            )
+            .parse()?),
         }
     }
 }
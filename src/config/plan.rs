@@ -0,0 +1,220 @@
+use anyhow::{Context, Result, bail};
+
+use crate::config::{AliasOrIndex, Config};
+
+#[derive(Debug, thiserror::Error)]
+#[error("Cycle in loads: {description}")]
+pub struct LoadsCycle {
+    pub indices: Vec<usize>,
+    description: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadPlanStep {
+    pub index: usize,
+    pub alias: String,
+    pub unloads: Vec<String>,
+}
+
+impl Config {
+    /// Resolves the models to load for `alias_or_index` with its `loads` dependencies first,
+    /// each model appearing once and the requested model last. Fails if a model in the plan
+    /// unloads another one in it.
+    pub fn get_load_plan(
+        &self,
+        alias_or_index: impl Into<AliasOrIndex>,
+    ) -> Result<Vec<LoadPlanStep>> {
+        let mut visited = Vec::new();
+
+        self.visit_loads(
+            self.get_model_index(alias_or_index)?,
+            &mut Vec::new(),
+            &mut visited,
+        )?;
+
+        let plan = visited
+            .into_iter()
+            .map(|index| {
+                let model_config = &self.models[index];
+
+                Ok(LoadPlanStep {
+                    index,
+                    alias: model_config.alias().to_string(),
+                    unloads: model_config
+                        .unloads
+                        .iter()
+                        .flatten()
+                        .map(|alias_or_index| {
+                            Ok(self.get_model_config(alias_or_index)?.alias().to_string())
+                        })
+                        .collect::<Result<_>>()
+                        .with_context(|| format!("Invalid unloads in config.models[{index}]"))?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for step in &plan {
+            if let Some(unloaded) = step
+                .unloads
+                .iter()
+                .find(|unload| plan.iter().any(|other| other.alias == **unload))
+            {
+                bail!(
+                    "'{}' (models[{}]) unloads '{unloaded}', which is loaded along with it",
+                    step.alias,
+                    step.index
+                );
+            }
+        }
+
+        Ok(plan)
+    }
+
+    fn visit_loads(
+        &self,
+        index: usize,
+        visiting: &mut Vec<usize>,
+        visited: &mut Vec<usize>,
+    ) -> Result<()> {
+        if visited.contains(&index) {
+            return Ok(());
+        }
+
+        if let Some(position) = visiting.iter().position(|visiting| *visiting == index) {
+            let indices = visiting[position..].to_vec();
+
+            let description = indices
+                .iter()
+                .chain([&index])
+                .map(|index| format!("'{}' (models[{index}])", self.models[*index].alias()))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+
+            return Err(LoadsCycle {
+                indices,
+                description,
+            }
+            .into());
+        }
+
+        visiting.push(index);
+
+        for alias_or_index in self.models[index].loads.iter().flatten() {
+            let dependency = self
+                .get_model_index(alias_or_index)
+                .with_context(|| format!("Invalid loads in config.models[{index}]"))?;
+
+            self.visit_loads(dependency, visiting, visited)?;
+        }
+
+        visiting.pop();
+        visited.push(index);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn model(alias: &str, loads: Value) -> Value {
+        json!({
+            "type": "command",
+            "loads": loads,
+            "config": { "alias": alias, "argv": ["server"] }
+        })
+    }
+
+    fn config(models: Vec<Value>) -> Config {
+        serde_json::from_value(json!({ "models": models })).unwrap()
+    }
+
+    fn plan_aliases(config: &Config, alias: &str) -> Vec<String> {
+        config
+            .get_load_plan(alias)
+            .unwrap()
+            .into_iter()
+            .map(|step| step.alias)
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_are_loaded_first_and_once() {
+        let config = config(vec![
+            model("app", json!(["embed", "chat"])),
+            model("chat", json!(["embed"])),
+            model("embed", json!(null)),
+        ]);
+
+        assert_eq!(plan_aliases(&config, "app"), ["embed", "chat", "app"]);
+    }
+
+    #[test]
+    fn loads_can_reference_indices() {
+        let config = config(vec![model("app", json!([1])), model("embed", json!(null))]);
+
+        assert_eq!(plan_aliases(&config, "app"), ["embed", "app"]);
+    }
+
+    #[test]
+    fn unloads_are_resolved_to_aliases() {
+        let mut config = config(vec![model("a", json!(null)), model("b", json!(null))]);
+        config.models[0].unloads = Some(vec![1.into()]);
+
+        let plan = config.get_load_plan("a").unwrap();
+
+        assert_eq!(plan[0].unloads, ["b"]);
+    }
+
+    #[test]
+    fn unloading_a_model_of_the_same_plan_is_rejected() {
+        let mut config = config(vec![
+            model("app", json!(["chat", "embed"])),
+            model("chat", json!(null)),
+            model("embed", json!(null)),
+        ]);
+
+        config.models[1].unloads = Some(vec!["embed".into()]);
+
+        assert!(config.get_load_plan("chat").is_ok());
+        assert_eq!(
+            config.get_load_plan("app").unwrap_err().to_string(),
+            "'chat' (models[1]) unloads 'embed', which is loaded along with it"
+        );
+
+        config.models[1].unloads = None;
+        config.models[0].unloads = Some(vec!["chat".into()]);
+
+        assert!(config.get_load_plan("app").is_err());
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_members() {
+        let config = config(vec![
+            model("entry", json!(["a"])),
+            model("a", json!(["b"])),
+            model("b", json!(["a"])),
+        ]);
+
+        let err = config.get_load_plan("entry").unwrap_err();
+        let cycle = err.downcast_ref::<LoadsCycle>().unwrap();
+
+        assert_eq!(cycle.indices, [1, 2]);
+        assert_eq!(
+            err.to_string(),
+            "Cycle in loads: 'a' (models[1]) -> 'b' (models[2]) -> 'a' (models[1])"
+        );
+    }
+
+    #[test]
+    fn self_references_are_cycles() {
+        let config = config(vec![model("a", json!(["a"]))]);
+
+        let err = config.get_load_plan("a").unwrap_err();
+
+        assert!(err.downcast_ref::<LoadsCycle>().is_some());
+    }
+}
@@ -0,0 +1,25 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PortRange {
+    pub start: u16,
+    /// Inclusive
+    pub end: u16,
+}
+
+impl Default for PortRange {
+    fn default() -> Self {
+        Self {
+            start: 8100,
+            end: 8199,
+        }
+    }
+}
+
+/// Renders a port for command lines, with a placeholder for ports that are assigned on load
+pub fn render_port(port: Option<u16>) -> String {
+    port.map(|port| port.to_string())
+        .unwrap_or("<auto>".to_string())
+}
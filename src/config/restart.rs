@@ -0,0 +1,130 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF_SECS: u64 = 1;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(
+    tag = "policy",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case",
+    deny_unknown_fields
+)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Restart when the process exits unsuccessfully. Retries default to 3.
+    OnFailure {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_retries: Option<u32>,
+        /// Delay before the first restart, doubled for every following attempt. Defaults to 1.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        backoff_secs: Option<u64>,
+    },
+    /// Restart whenever the process exits. Retries are unlimited unless set.
+    Always {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_retries: Option<u32>,
+        /// Delay before the first restart, doubled for every following attempt. Defaults to 1.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        backoff_secs: Option<u64>,
+    },
+}
+
+impl RestartPolicy {
+    /// Returns the delay before the next restart, or `None` if the process should stay down.
+    pub fn next_delay(&self, success: bool, retries: u32) -> Option<Duration> {
+        let (max_retries, backoff_secs) = match self {
+            RestartPolicy::Never => return None,
+            RestartPolicy::OnFailure { .. } if success => return None,
+            RestartPolicy::OnFailure {
+                max_retries,
+                backoff_secs,
+            } => (
+                Some(max_retries.unwrap_or(DEFAULT_MAX_RETRIES)),
+                backoff_secs,
+            ),
+            RestartPolicy::Always {
+                max_retries,
+                backoff_secs,
+            } => (*max_retries, backoff_secs),
+        };
+
+        if max_retries.is_some_and(|max_retries| retries >= max_retries) {
+            return None;
+        }
+
+        let backoff_secs = backoff_secs.unwrap_or(DEFAULT_BACKOFF_SECS);
+
+        Some(Duration::from_secs(
+            backoff_secs.saturating_mul(2u64.saturating_pow(retries)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn never_restarts() {
+        assert_eq!(RestartPolicy::Never.next_delay(false, 0), None);
+    }
+
+    #[test]
+    fn on_failure_ignores_successful_exits() {
+        let policy = RestartPolicy::OnFailure {
+            max_retries: None,
+            backoff_secs: None,
+        };
+
+        assert_eq!(policy.next_delay(true, 0), None);
+        assert_eq!(policy.next_delay(false, 0), secs(1));
+    }
+
+    #[test]
+    fn on_failure_gives_up_after_default_retries() {
+        let policy = RestartPolicy::OnFailure {
+            max_retries: None,
+            backoff_secs: None,
+        };
+
+        assert_eq!(policy.next_delay(false, DEFAULT_MAX_RETRIES - 1), secs(4));
+        assert_eq!(policy.next_delay(false, DEFAULT_MAX_RETRIES), None);
+    }
+
+    #[test]
+    fn always_restarts_without_limit_unless_set() {
+        let unlimited = RestartPolicy::Always {
+            max_retries: None,
+            backoff_secs: Some(2),
+        };
+        let limited = RestartPolicy::Always {
+            max_retries: Some(1),
+            backoff_secs: Some(2),
+        };
+
+        assert_eq!(unlimited.next_delay(true, 10), secs(2 * 1024));
+        assert_eq!(limited.next_delay(true, 0), secs(2));
+        assert_eq!(limited.next_delay(true, 1), None);
+    }
+
+    #[test]
+    fn backoff_doubles_and_saturates() {
+        let policy = RestartPolicy::Always {
+            max_retries: None,
+            backoff_secs: Some(3),
+        };
+
+        assert_eq!(policy.next_delay(false, 0), secs(3));
+        assert_eq!(policy.next_delay(false, 1), secs(6));
+        assert_eq!(policy.next_delay(false, 2), secs(12));
+        assert_eq!(policy.next_delay(false, 100), secs(u64::MAX));
+    }
+}
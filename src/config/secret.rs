@@ -0,0 +1,218 @@
+use anyhow::{Context, Result, anyhow, bail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    fmt::Debug,
+    io::Read,
+    path::Path,
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use utils_rs::secret::Secret;
+
+const REDACTED: &str = "<redacted>";
+/// Longest a secret command may run before it is killed
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum SecretSource {
+    Literal(Secret<String>),
+    /// Read from an environment variable of the router process
+    Env {
+        env: String,
+    },
+    /// Read from a file, `~` is expanded and relative paths are resolved against the config file's directory
+    File {
+        file: PathBuf,
+    },
+    /// Read from the stdout of a command, e.g. `["pass", "show", "groq"]`. Killed after 10s.
+    Command {
+        command: Vec<String>,
+    },
+}
+
+impl Debug for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Literal(_) => f.write_str("literal"),
+            SecretSource::Env { env } => f.write_fmt(format_args!("env '{env}'")),
+            SecretSource::File { file } => f.write_fmt(format_args!("file {file:?}")),
+            SecretSource::Command { command } => {
+                f.write_fmt(format_args!("command '{}'", command.join(" ")))
+            }
+        }
+    }
+}
+
+/// A secret given literally or read from a source when the config is parsed.
+///
+/// Serializes as its source with literal values redacted, so configs can be returned by the API.
+#[derive(Clone, Deserialize, JsonSchema)]
+#[serde(from = "SecretSource")]
+#[schemars(with = "SecretSource")]
+pub struct SecretConfig {
+    source: SecretSource,
+    resolved: Option<Secret<String>>,
+}
+
+impl From<SecretSource> for SecretConfig {
+    fn from(source: SecretSource) -> Self {
+        Self {
+            source,
+            resolved: None,
+        }
+    }
+}
+
+impl Serialize for SecretConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.source {
+            SecretSource::Literal(_) => serializer.serialize_str(REDACTED),
+            source => source.serialize(serializer),
+        }
+    }
+}
+
+impl Debug for SecretConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("SecretConfig({:?})", self.source))
+    }
+}
+
+impl SecretConfig {
+    /// The secret value, `None` if it is read from a source that hasn't been resolved.
+    pub fn secret(&self) -> Option<&Secret<String>> {
+        match &self.source {
+            SecretSource::Literal(secret) => Some(secret),
+            _ => self.resolved.as_ref(),
+        }
+    }
+
+    /// Reads the secret from its source.
+    pub fn resolve(&mut self, config_dir: &Path) -> Result<()> {
+        let value = match &self.source {
+            SecretSource::Literal(_) => return Ok(()),
+            SecretSource::Env { env } => std::env::var(env).map_err(|err| anyhow!("{err}")),
+            SecretSource::File { file } => {
+                let file = match file.strip_prefix("~") {
+                    Ok(rest) => std::env::home_dir()
+                        .ok_or(anyhow!("Failed to find the home directory"))?
+                        .join(rest),
+                    Err(_) => config_dir.join(file),
+                };
+
+                std::fs::read_to_string(file)
+                    .map(|content| content.trim_end().to_string())
+                    .map_err(|err| anyhow!("{err}"))
+            }
+            SecretSource::Command { command } => read_command(command, COMMAND_TIMEOUT),
+        };
+
+        let value =
+            value.with_context(|| format!("Failed to read the secret from {:?}", self.source))?;
+
+        self.resolved = Some(Secret::new(value));
+
+        Ok(())
+    }
+}
+
+/// Runs the command, killing it if it doesn't exit within `timeout`.
+fn read_command(command: &[String], timeout: Duration) -> Result<String> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("Command is empty");
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read while waiting so a chatty command can't block on a full pipe
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+
+            bail!("Timed out after {}s", timeout.as_secs());
+        }
+
+        std::thread::sleep(COMMAND_POLL_INTERVAL);
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+
+    if !status.success() {
+        bail!(
+            "{status} ({})",
+            String::from_utf8_lossy(&stderr.join().unwrap_or_default()).trim_end()
+        );
+    }
+
+    Ok(String::from_utf8(stdout)?.trim_end().to_string())
+}
+
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+
+        output
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn commands_are_read_from_stdout() {
+        assert_eq!(
+            read_command(&command(&["sh", "-c", "echo secret"]), COMMAND_TIMEOUT).unwrap(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn failing_commands_report_stderr() {
+        let err = read_command(
+            &command(&["sh", "-c", "echo denied >&2; exit 3"]),
+            COMMAND_TIMEOUT,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("denied"), "{err}");
+    }
+
+    #[test]
+    fn hanging_commands_time_out() {
+        let started = Instant::now();
+
+        let err = read_command(&command(&["sleep", "60"]), Duration::from_millis(200)).unwrap_err();
+
+        assert!(err.to_string().starts_with("Timed out"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
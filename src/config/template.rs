@@ -0,0 +1,280 @@
+use anyhow::{Result, bail};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::config::ModelConfig;
+
+/// Config-wide defaults merged beneath every model of a type
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DefaultsConfig {
+    /// Merged into the `config` of every llama-cpp model, e.g. `ctx-size` or `host`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub llama_cpp: Option<Map<String, Value>>,
+}
+
+/// Resolves `extends` and `defaults` in a raw config before it is deserialized.
+///
+/// Every model is deep-merged on top of its template chain, objects are merged key by key and
+/// anything else is replaced. Defaults are merged beneath the `config` of models of their type.
+pub fn resolve_templates(config: &mut Value) -> Result<()> {
+    let templates = config
+        .get("templates")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    let llama_cpp_defaults = config
+        .pointer("/defaults/llama-cpp")
+        .cloned()
+        .filter(Value::is_object);
+
+    let Some(models) = config.get_mut("models").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+
+    for (index, model) in models.iter_mut().enumerate() {
+        let mut resolved = Value::Object(Map::new());
+        let mut chain = Vec::new();
+        let mut extends = model.get("extends").cloned();
+
+        while let Some(name) = extends {
+            let Some(name) = name.as_str() else {
+                bail!("models[{index}]: Expected `extends` to be a template name, found {name}");
+            };
+
+            if chain.iter().any(|(visited, _)| visited == name) {
+                bail!(
+                    "models[{index}]: Cycle in templates: {} -> {name}",
+                    chain_names(&chain)
+                );
+            }
+
+            let Some(template) = templates.get(name) else {
+                bail!("models[{index}]: Template '{name}' not found");
+            };
+
+            extends = template.get("extends").cloned();
+            chain.push((name.to_string(), template.clone()));
+        }
+
+        for (_, template) in chain.into_iter().rev() {
+            merge(&mut resolved, template);
+        }
+
+        merge(&mut resolved, model.take());
+
+        if let Some(defaults) = &llama_cpp_defaults
+            && resolved.get("type").and_then(Value::as_str) == Some("llama-cpp")
+        {
+            let mut config = defaults.clone();
+
+            if let Some(model_config) = resolved.get_mut("config") {
+                merge(&mut config, model_config.take());
+            }
+
+            resolved["config"] = config;
+        }
+
+        *model = resolved;
+    }
+
+    Ok(())
+}
+
+fn chain_names(chain: &[(String, Value)]) -> String {
+    chain
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Models extending a template only have to be complete after merging
+pub(super) fn models_schema(generator: &mut SchemaGenerator) -> Schema {
+    let model_config = generator.subschema_for::<ModelConfig>();
+
+    json_schema!({
+        "type": "array",
+        "items": {
+            "anyOf": [
+                model_config,
+                {
+                    "description": "Partial model config completed by the template it extends",
+                    "type": "object",
+                    "properties": {
+                        "extends": { "type": "string" }
+                    },
+                    "required": ["extends"]
+                }
+            ]
+        }
+    })
+}
+
+/// Templates are partial model configs, see `ModelConfig::extends`
+pub(super) fn templates_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": ["object", "null"],
+        "additionalProperties": {
+            "type": "object",
+            "properties": {
+                "extends": { "type": "string" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::{Config, ModelTypeConfig};
+
+    fn resolve(mut config: Value) -> Value {
+        resolve_templates(&mut config).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn models_are_merged_over_their_template_chain() {
+        let config = resolve(json!({
+            "templates": {
+                "base": {
+                    "type": "llama-cpp",
+                    "idle-timeout-secs": 300,
+                    "config": { "ctx-size": 4096, "flash-attn": true }
+                },
+                "large": { "extends": "base", "config": { "ctx-size": 32768 } }
+            },
+            "models": [{
+                "extends": "large",
+                "config": { "alias": "m", "model": "m.gguf", "flash-attn": false }
+            }]
+        }));
+
+        let model = &config["models"][0];
+
+        assert_eq!(model["type"], "llama-cpp");
+        assert_eq!(model["idle-timeout-secs"], 300);
+        assert_eq!(
+            model["config"],
+            json!({ "ctx-size": 32768, "flash-attn": false, "alias": "m", "model": "m.gguf" })
+        );
+    }
+
+    #[test]
+    fn arrays_are_replaced() {
+        let config = resolve(json!({
+            "templates": {
+                "server": {
+                    "type": "command",
+                    "config": { "argv": ["server", "--port", "{port}"] }
+                }
+            },
+            "models": [{
+                "extends": "server",
+                "config": { "alias": "m", "argv": ["other-server"] }
+            }]
+        }));
+
+        assert_eq!(
+            config["models"][0]["config"]["argv"],
+            json!(["other-server"])
+        );
+    }
+
+    #[test]
+    fn defaults_only_apply_to_their_type() {
+        let config = resolve(json!({
+            "defaults": { "llama-cpp": { "host": "0.0.0.0", "ctx-size": 4096 } },
+            "models": [
+                { "type": "llama-cpp", "config": { "alias": "a", "model": "a.gguf", "ctx-size": 8192 } },
+                { "type": "command", "config": { "alias": "b", "argv": ["server"] } }
+            ]
+        }));
+
+        assert_eq!(
+            config["models"][0]["config"],
+            json!({ "host": "0.0.0.0", "ctx-size": 8192, "alias": "a", "model": "a.gguf" })
+        );
+        assert_eq!(
+            config["models"][1]["config"],
+            json!({ "alias": "b", "argv": ["server"] })
+        );
+    }
+
+    #[test]
+    fn resolved_models_deserialize() {
+        let config = resolve(json!({
+            "defaults": { "llama-cpp": { "host": "0.0.0.0" } },
+            "templates": {
+                "base": { "type": "llama-cpp", "config": { "ctx-size": 4096 } },
+                "server": { "type": "command", "config": { "argv": ["server"] } }
+            },
+            "models": [
+                { "extends": "base", "config": { "alias": "a", "model": "a.gguf" } },
+                { "extends": "server", "config": { "alias": "b" } }
+            ]
+        }));
+
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        assert!(matches!(
+            &config.models[0].config,
+            ModelTypeConfig::LlamaCpp(x) if x.host.as_deref() == Some("0.0.0.0")
+        ));
+        assert!(matches!(
+            &config.models[1].config,
+            ModelTypeConfig::Command(x) if x.argv == ["server"]
+        ));
+    }
+
+    #[test]
+    fn templates_producing_invalid_models_fail_to_deserialize() {
+        let config = resolve(json!({
+            "templates": { "base": { "type": "command", "config": { "argv": "server" } } },
+            "models": [{ "extends": "base", "config": { "alias": "a" } }]
+        }));
+
+        assert!(serde_json::from_value::<Config>(config).is_err());
+    }
+
+    #[test]
+    fn cycles_and_missing_templates_are_rejected() {
+        let mut config = json!({
+            "templates": { "a": { "extends": "b" }, "b": { "extends": "a" } },
+            "models": [{ "extends": "a" }]
+        });
+
+        let error = resolve_templates(&mut config).unwrap_err().to_string();
+
+        assert!(error.contains("a -> b -> a"), "{error}");
+
+        let mut config = json!({ "models": [{ "extends": "missing" }] });
+
+        assert!(resolve_templates(&mut config).is_err());
+    }
+}
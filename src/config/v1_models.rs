@@ -0,0 +1,31 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// Options for the aggregated `/v1/models` listing
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct V1ModelsConfig {
+    /// Seconds a provider's model list is reused for. Defaults to 60.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl_secs: Option<u64>,
+    /// Seconds to wait for each provider before its models are flagged as failed. Defaults to 5.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Also list configured models that aren't loaded. Can be overridden with `?unloaded=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_unloaded: Option<bool>,
+}
+
+impl V1ModelsConfig {
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs.unwrap_or(DEFAULT_CACHE_TTL_SECS))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+}
@@ -3,35 +3,37 @@ mod cli;
 mod commands;
 mod config;
 mod models;
+mod usage;
 
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result, bail};
 use api::serve_sync;
-use cli::{Cli, CliCommand, ServeArgs};
-use config::Config;
+use cli::{Cli, CliCommand, RouterArgs, ServeArgs};
+use config::{CONFIG_FILE_NAMES, Config};
 use daemonize::Daemonize;
 use schemars::schema_for;
 use std::{
+    env::current_dir,
     fs::{File, exists},
     net::SocketAddr,
     path::{Path, PathBuf},
 };
-use utils_rs::prelude::{FindWalkingBack, ResolveEnvParts};
+use utils_rs::prelude::ResolveEnvParts;
 
-const DEFAULT_PATH: &str = "~/.config/hrdr/hrdr.json";
+const DEFAULT_DIR: &str = "~/.config/hrdr";
 
 fn resolve_config_path(provided_path: Option<impl AsRef<Path>>) -> Result<PathBuf> {
     let path = if let Some(path) = provided_path {
         path.as_ref().resolve_env_parts()
     } else {
-        PathBuf::from(DEFAULT_PATH)
-            .file_name()
-            .ok_or(anyhow!("No file name in default path"))
-            .and_then(|file_name| {
-                file_name
-                    .find_walking_back()
-                    .context("Failed walking back to look for file name")
-            })?
-            .unwrap_or_else(|| DEFAULT_PATH.resolve_env_parts())
+        find_config_walking_back()?.unwrap_or_else(|| {
+            let default_dir = DEFAULT_DIR.resolve_env_parts();
+
+            CONFIG_FILE_NAMES
+                .iter()
+                .map(|file_name| default_dir.join(file_name))
+                .find(|path| path.exists())
+                .unwrap_or(default_dir.join(CONFIG_FILE_NAMES[0]))
+        })
     };
 
     if !exists(&path)? {
@@ -41,6 +43,24 @@ fn resolve_config_path(provided_path: Option<impl AsRef<Path>>) -> Result<PathBu
     Ok(path)
 }
 
+/// Looks for any of the config file names in the current directory and its ancestors, nearest first.
+fn find_config_walking_back() -> Result<Option<PathBuf>> {
+    let current_dir = current_dir().context("Failed walking back to look for a config file")?;
+
+    Ok(current_dir.ancestors().find_map(|dir| {
+        CONFIG_FILE_NAMES
+            .iter()
+            .map(|file_name| dir.join(file_name))
+            .find(|path| path.exists())
+    }))
+}
+
+fn print_config_warnings(config: &Config) {
+    for diagnostic in config.check() {
+        eprintln!("{diagnostic}");
+    }
+}
+
 fn main() -> Result<()> {
     match Cli::command() {
         CliCommand::Schema => {
@@ -49,6 +69,11 @@ fn main() -> Result<()> {
                 serde_json::to_string_pretty(&schema_for!(Config)).unwrap()
             );
         }
+        CliCommand::Check { config_path } => {
+            let config_path = resolve_config_path(config_path)?;
+
+            commands::check_sync(&config_path)?;
+        }
         CliCommand::Start(ServeArgs {
             ip,
             config_path,
@@ -68,6 +93,8 @@ fn main() -> Result<()> {
             let config_path = resolve_config_path(config_path)?;
             let config = Config::load(&config_path)?;
 
+            print_config_warnings(&config);
+
             let stdout = File::create(config.detach.out_file_path)?;
             let stderr = File::create(config.detach.err_file_path)?;
 
@@ -92,7 +119,9 @@ fn main() -> Result<()> {
             let address = SocketAddr::new(ip, port);
 
             let config_path = resolve_config_path(config_path)?;
-            Config::load(&config_path)?;
+            let config = Config::load(&config_path)?;
+
+            print_config_warnings(&config);
 
             serve_sync(&address, config_path)?;
         }
@@ -117,6 +146,16 @@ fn main() -> Result<()> {
                 println!("{err}")
             }
         }
+        CliCommand::Reload(RouterArgs { port, key }) => {
+            commands::reload_sync(port, key)?;
+        }
+        CliCommand::Usage {
+            router: RouterArgs { port, key },
+            since,
+            until,
+        } => {
+            commands::usage_sync(port, key, since, until)?;
+        }
         CliCommand::Models {
             config_path,
             command,
@@ -127,33 +166,39 @@ fn main() -> Result<()> {
                 cli::ModelCommand::List => {
                     commands::list_sync(&config_path)?;
                 }
-                cli::ModelCommand::Config { alias_or_index } => {
-                    commands::config_sync(&config_path, alias_or_index)?;
+                cli::ModelCommand::Config {
+                    argv,
+                    alias_or_index,
+                } => {
+                    commands::config_sync(&config_path, alias_or_index, argv)?;
+                }
+                cli::ModelCommand::Plan { alias_or_index } => {
+                    commands::plan_sync(&config_path, alias_or_index)?;
                 }
                 cli::ModelCommand::Load {
-                    port,
+                    router: RouterArgs { port, key },
                     tail,
                     json,
                     alias_or_index,
                 } => {
-                    commands::load_sync(alias_or_index, port, tail, json)?;
+                    commands::load_sync(alias_or_index, port, key, tail, json)?;
                 }
-                cli::ModelCommand::Loaded { port } => {
-                    commands::loaded_sync(port)?;
+                cli::ModelCommand::Loaded(RouterArgs { port, key }) => {
+                    commands::loaded_sync(port, key)?;
                 }
                 cli::ModelCommand::Unload {
-                    port,
+                    router: RouterArgs { port, key },
                     alias_or_index,
                 } => {
-                    commands::unload_sync(alias_or_index, port)?;
+                    commands::unload_sync(alias_or_index, port, key)?;
                 }
                 cli::ModelCommand::Logs {
-                    port,
+                    router: RouterArgs { port, key },
                     tail,
                     json,
                     alias_or_index,
                 } => {
-                    commands::logs_sync(alias_or_index, port, tail, json)?;
+                    commands::logs_sync(alias_or_index, port, key, tail, json)?;
                 }
             }
         }
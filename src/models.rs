@@ -1,76 +1,194 @@
-use crate::config::{AliasOrIndex, Config, LlamaCppModelConfig, ModelConfig, ModelTypeConfig};
-use anyhow::Result;
-use async_recursion::async_recursion;
-use chrono::{DateTime, Utc};
+mod argv;
+mod concurrency;
+mod logs;
+mod spawned;
+
+pub use concurrency::QueueFull;
+pub use logs::*;
+pub use spawned::LaunchCommand;
+
+use crate::config::{
+    AliasOrIndex, Config, GroupConfig, LlamaCppModelConfig, LoadPlanStep, ModelConfig, ModelSource,
+    ModelTypeConfig, PortRange, SecretConfig, render_port,
+};
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use concurrency::{Concurrency, InFlight};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, process::Stdio, sync::Arc};
-use tokio::{
-    io::AsyncReadExt,
-    process::{Child, Command},
-    sync::{Mutex, broadcast},
+use spawned::{Spawned, Supervision};
+use std::{
+    collections::{HashMap, HashSet},
+    net::TcpListener,
+    sync::Arc,
+    time::Duration,
 };
+use tokio::sync::{Mutex, watch};
+use utils_rs::{option::as_bool::AsBool, secret::Secret};
+
+const IDLE_REAPER_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_READY_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_STOP_TIMEOUT_SECS: u64 = 10;
+const STDERR_TAIL_LINES: usize = 20;
+/// Attempts to start a model with an assigned port when the port turns out to be taken
+const MAX_PORT_ATTEMPTS: usize = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TimestampedMessage {
-    pub timestamp: DateTime<Utc>,
-    pub message: String,
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum ModelState {
+    Starting,
+    Ready,
+    Failed { reason: String },
 }
 
-impl TimestampedMessage {
-    pub fn new(message: impl Display) -> Self {
-        Self {
-            timestamp: Utc::now(),
-            message: message.to_string(),
+impl ModelState {
+    pub fn is_starting(&self) -> bool {
+        matches!(self, ModelState::Starting)
+    }
+
+    async fn wait_until_started(state: &mut watch::Receiver<ModelState>) -> Self {
+        match state.wait_for(|state| !state.is_starting()).await {
+            Ok(state) => state.clone(),
+            Err(_) => ModelState::Failed {
+                reason: "Supervisor stopped before the model was ready".into(),
+            },
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "stream")]
-pub enum Log {
-    StdOut(TimestampedMessage),
-    StdErr(TimestampedMessage),
+/// Touches the model's last activity timestamp when created and again when
+/// dropped, so it can be held for the lifetime of a proxied response.
+/// Also holds the model's concurrency slot for as long as it lives, which keeps
+/// the idle reaper and evictions from unloading the model in the meantime.
+pub struct RequestGuard {
+    last_activity: Arc<std::sync::Mutex<DateTime<Utc>>>,
+    _in_flight: InFlight,
 }
 
-impl Display for Log {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let (stream, TimestampedMessage { timestamp, message }) = match &self {
-            Log::StdOut(timestamped_message) => ("StdOut", timestamped_message),
-            Log::StdErr(timestamped_message) => ("StdErr", timestamped_message),
-        };
+impl RequestGuard {
+    fn new(last_activity: Arc<std::sync::Mutex<DateTime<Utc>>>, in_flight: InFlight) -> Self {
+        *last_activity.lock().unwrap() = Utc::now();
 
-        f.write_fmt(format_args!("{stream} - {timestamp} - {message}"))
+        Self {
+            last_activity,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        *self.last_activity.lock().unwrap() = Utc::now();
     }
 }
 
-struct Spawned {
-    io_sender: broadcast::Sender<Log>,
-    logs: Arc<Mutex<Vec<Log>>>,
-    _child: Child, // Unused, but will be killed on drop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoadedModelStatus {
+    pub config: ModelConfig,
+    #[serde(flatten)]
+    pub state: ModelState,
+    pub loaded_at: DateTime<Utc>,
+    pub last_activity: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    pub in_flight: usize,
+    pub queued: usize,
 }
 
-pub struct LogsAndTailReceiver {
-    pub tail_receiver: broadcast::Receiver<Log>,
-    pub logs: Vec<Log>,
+struct LoadedModel {
+    config: ModelConfig,
+    /// Whether `config` has a port assigned from the config-wide port range
+    port_assigned: bool,
+    spawned: Option<Spawned>,
+    state: watch::Receiver<ModelState>,
+    loaded_at: DateTime<Utc>,
+    last_activity: Arc<std::sync::Mutex<DateTime<Utc>>>,
+    idle_timeout_secs: Option<u64>,
+    concurrency: Arc<Concurrency>,
 }
 
-impl LogsAndTailReceiver {
-    async fn from_ref(spawned: &Spawned) -> Self {
-        LogsAndTailReceiver {
-            logs: spawned.logs.lock().await.clone(),
-            tail_receiver: spawned.io_sender.subscribe(),
+impl LoadedModel {
+    async fn stop(self) -> ModelConfig {
+        if let Some(spawned) = self.spawned {
+            spawned.stop().await;
         }
+
+        self.config
+    }
+
+    fn status(&self) -> LoadedModelStatus {
+        LoadedModelStatus {
+            config: self.config.clone(),
+            state: self.state.borrow().clone(),
+            loaded_at: self.loaded_at,
+            last_activity: *self.last_activity.lock().unwrap(),
+            idle_timeout_secs: self.idle_timeout_secs,
+            in_flight: self.concurrency.in_flight(),
+            queued: self.concurrency.queued(),
+        }
+    }
+
+    /// Returns the config as written in the config file, without an assigned port.
+    fn configured_config(&self) -> ModelConfig {
+        let mut config = self.config.clone();
+
+        if self.port_assigned
+            && let Some(port) = config.config.port_mut()
+        {
+            *port = None;
+        }
+
+        config
+    }
+
+    fn cost(&self) -> u64 {
+        self.config.cost.unwrap_or(0)
+    }
+
+    /// Whether requests are being served or waiting, so the model must not be evicted
+    fn is_busy(&self) -> bool {
+        self.concurrency.in_flight() > 0 || self.concurrency.queued() > 0
+    }
+
+    fn is_idle(&self, now: DateTime<Utc>) -> bool {
+        !self.state.borrow().is_starting()
+            && !self.is_busy()
+            && self.idle_timeout_secs.is_some_and(|idle_timeout_secs| {
+                now - *self.last_activity.lock().unwrap()
+                    > TimeDelta::seconds(idle_timeout_secs as i64)
+            })
     }
 }
 
-struct LoadedModel {
-    config: ModelConfig,
-    spawned: Option<Spawned>,
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReloadReport {
+    pub unloaded: Vec<String>,
+    pub restarted: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Models that failed to restart or unload, with the reason
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<String>,
+    /// Top-level settings that changed and when they take effect
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub settings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoadReport {
+    pub config: ModelConfig,
+    /// Models unloaded to fit the loaded model(s) within their groups and the config-wide budget
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evicted: Vec<String>,
 }
 
 #[derive(Default, Clone)]
 pub struct Models {
     loaded: Arc<Mutex<HashMap<String, LoadedModel>>>,
+    /// Per alias, so loading one model on demand doesn't hold up requests for another
+    on_demand: Arc<std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    reloading: Arc<Mutex<()>>,
 }
 
 // #[derive(Debug, thiserror::Error)]
@@ -89,204 +207,1089 @@ impl Models {
         Some(LogsAndTailReceiver::from_ref(spawned).await)
     }
 
-    pub async fn get_loaded_config(&self, alias: &str) -> Option<ModelConfig> {
+    pub async fn get_loaded_statuses(&self) -> Vec<LoadedModelStatus> {
         self.loaded
             .lock()
             .await
+            .values()
+            .map(LoadedModel::status)
+            .collect()
+    }
+
+    /// Waits for a free concurrency slot on the ready model and returns its config, or fails if its
+    /// queue is full. `None` if the model isn't loaded and ready, also when it was unloaded or
+    /// restarted while waiting.
+    pub async fn begin_request(
+        &self,
+        alias: &str,
+    ) -> Result<Option<(ModelConfig, RequestGuard)>, QueueFull> {
+        // Touched under the lock so the reaper can't unload the model before a slot is acquired
+        let Some((last_activity, concurrency)) = self.loaded.lock().await.get(alias).map(|m| {
+            *m.last_activity.lock().unwrap() = Utc::now();
+
+            (m.last_activity.clone(), m.concurrency.clone())
+        }) else {
+            return Ok(None);
+        };
+
+        let in_flight = concurrency.clone().acquire(alias).await?;
+
+        // Once the slot is held the model is busy, so it stays loaded if it still is now
+        let loaded_models = self.loaded.lock().await;
+
+        Ok(loaded_models
             .get(alias)
-            .map(|m| m.config.clone())
+            .filter(|m| {
+                Arc::ptr_eq(&m.concurrency, &concurrency)
+                    && matches!(*m.state.borrow(), ModelState::Ready)
+            })
+            .map(|m| {
+                (
+                    m.config.clone(),
+                    RequestGuard::new(last_activity, in_flight),
+                )
+            }))
     }
 
-    pub async fn get_loaded_configs(&self) -> Vec<ModelConfig> {
-        self.loaded
+    /// Periodically unloads models that have been idle for longer than their idle timeout.
+    pub fn spawn_idle_reaper(&self) {
+        let models = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_REAPER_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                models.unload_idle().await;
+            }
+        });
+    }
+
+    async fn unload_idle(&self) {
+        let now = Utc::now();
+
+        let idle = {
+            let mut loaded_models = self.loaded.lock().await;
+
+            let aliases = loaded_models
+                .iter()
+                .filter(|(_, loaded_model)| loaded_model.is_idle(now))
+                .map(|(alias, _)| alias.clone())
+                .collect::<Vec<_>>();
+
+            aliases
+                .into_iter()
+                .filter_map(|alias| loaded_models.remove(&alias))
+                .collect::<Vec<_>>()
+        };
+
+        for loaded_model in idle {
+            println!(
+                "Unloading model '{}' after {}s of inactivity",
+                loaded_model.config.alias(),
+                (now - *loaded_model.last_activity.lock().unwrap()).num_seconds()
+            );
+
+            loaded_model.stop().await;
+        }
+    }
+
+    /// Waits for the model to leave the starting state and returns its config and resulting state,
+    /// or `None` if no model with `alias` is loaded.
+    pub async fn wait_until_started(&self, alias: &str) -> Option<(ModelConfig, ModelState)> {
+        let (model_config, mut state) = self
+            .loaded
             .lock()
             .await
-            .values()
-            .map(|m| m.config.clone())
-            .collect()
+            .get(alias)
+            .map(|m| (m.config.clone(), m.state.clone()))?;
+
+        Some((
+            model_config,
+            ModelState::wait_until_started(&mut state).await,
+        ))
+    }
+
+    /// Returns the loaded config for `alias`, loading it first if needed. A failed instance is
+    /// replaced. Concurrent calls for the same alias are serialized so a model is only spawned once.
+    pub async fn load_on_demand(&self, config: &Config, alias: &str) -> Result<ModelConfig> {
+        let lock = self
+            .on_demand
+            .lock()
+            .unwrap()
+            .entry(alias.to_string())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        match self.wait_until_started(alias).await {
+            Some((model_config, ModelState::Ready)) => return Ok(model_config),
+            Some((_, ModelState::Failed { reason })) => {
+                println!("Replacing model '{alias}' which failed: {reason}");
+
+                self.unload(alias).await?;
+            }
+            Some((_, ModelState::Starting)) | None => {}
+        }
+
+        let index = config.get_on_demand_model_index(alias)?;
+
+        println!("Loading model for alias '{alias}' on demand (config.models[{index}])");
+
+        let (load_report, _) = self.load(config, index).await?;
+
+        Ok(load_report.config)
     }
 
+    /// Removes the model and waits for its process, if any, to exit.
     pub async fn unload(&self, alias: &str) -> Result<Option<ModelConfig>> {
-        let mut loaded_models = self.loaded.lock().await;
+        let removed = self.loaded.lock().await.remove(alias);
 
-        Ok(loaded_models.remove(alias).map(|m| m.config))
+        Ok(match removed {
+            Some(loaded_model) => Some(loaded_model.stop().await),
+            None => None,
+        })
     }
 
-    #[async_recursion]
+    /// Diffs the loaded models against `config`, restarting models whose config or launch command
+    /// changed and unloading models that are no longer configured. Unchanged dependencies of
+    /// restarted models keep running. Models that fail to do so are reported without aborting the
+    /// reload.
+    pub async fn reload(&self, previous: &Config, config: &Config) -> Result<ReloadReport> {
+        let _guard = self.reloading.lock().await;
+
+        let started_at = Utc::now();
+        let mut report = ReloadReport::default();
+
+        let loaded_configs = self
+            .loaded
+            .lock()
+            .await
+            .values()
+            .map(LoadedModel::configured_config)
+            .collect::<Vec<_>>();
+
+        for loaded_config in loaded_configs {
+            let alias = loaded_config.alias().to_string();
+
+            // Changed dependencies are restarted along with the models that load them
+            if self
+                .loaded
+                .lock()
+                .await
+                .get(&alias)
+                .is_some_and(|loaded_model| loaded_model.loaded_at > started_at)
+            {
+                report.restarted.push(alias);
+
+                continue;
+            }
+
+            let unchanged = config
+                .models
+                .iter()
+                .filter(|m| m.alias() == alias)
+                .find(|m| is_unchanged(previous, &loaded_config, config, m));
+
+            if let Some(model_config) = unchanged {
+                if let Some(loaded) = self.loaded.lock().await.get_mut(&alias) {
+                    loaded.idle_timeout_secs =
+                        model_config.idle_timeout_secs.or(config.idle_timeout_secs);
+                }
+
+                report.unchanged.push(alias);
+            } else if let Ok(index) = config.get_on_demand_model_index(&alias) {
+                println!("Restarting model '{alias}' with changed config (config.models[{index}])");
+
+                match self.load(config, index).await {
+                    Ok(_) => report.restarted.push(alias),
+                    Err(err) => report.failed.push(format!("{alias}: {err:#}")),
+                }
+            } else {
+                println!("Unloading model '{alias}' as it is no longer configured");
+
+                match self.unload(&alias).await {
+                    Ok(_) => report.unloaded.push(alias),
+                    Err(err) => report.failed.push(format!("{alias}: {err:#}")),
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Loads the model along with its `loads` dependencies, dependencies first. Dependencies that
+    /// are already running with the same config are left alone, the requested model is restarted.
     pub async fn load(
         &self,
         config: &Config,
-        alias_or_index: impl Into<AliasOrIndex> + Send + 'async_recursion,
-    ) -> Result<(ModelConfig, Option<LogsAndTailReceiver>)> {
-        let mut loaded_models = self.loaded.lock().await;
+        alias_or_index: impl Into<AliasOrIndex>,
+    ) -> Result<(LoadReport, Option<LogsAndTailReceiver>)> {
+        let plan = config.get_load_plan(alias_or_index)?;
+
+        let protected = plan
+            .iter()
+            .map(|step| step.alias.as_str())
+            .collect::<Vec<_>>();
+
+        let requested = protected
+            .last()
+            .expect("Load plan contains at least the requested model");
 
-        let model_config = config.get_model_config(alias_or_index)?;
+        let mut evicted = Vec::new();
+        let mut loaded = None;
+
+        for step in &plan {
+            if step.alias != *requested && self.is_running(config, step).await {
+                continue;
+            }
+
+            let (model_config, step_evicted, logs_and_tail_receiver) =
+                self.load_step(config, step, &protected, requested).await?;
+
+            evicted.extend(step_evicted);
+            loaded = Some((model_config, logs_and_tail_receiver));
+        }
+
+        let (config, logs_and_tail_receiver) =
+            loaded.expect("Load plan contains at least the requested model");
+
+        Ok((LoadReport { config, evicted }, logs_and_tail_receiver))
+    }
+
+    /// Whether the model of `step` is starting or ready with the config it has in `config`
+    async fn is_running(&self, config: &Config, step: &LoadPlanStep) -> bool {
+        self.loaded
+            .lock()
+            .await
+            .get(&step.alias)
+            .is_some_and(|loaded_model| {
+                !matches!(*loaded_model.state.borrow(), ModelState::Failed { .. })
+                    && is_unchanged(
+                        config,
+                        &loaded_model.configured_config(),
+                        config,
+                        &config.models[step.index],
+                    )
+            })
+    }
+
+    /// Loads a single model, stopping what it unloads and evicting models it doesn't fit with.
+    /// Models in `protected` are part of the same plan and never evicted. Models other than the
+    /// `requested` one are never stopped while busy.
+    async fn load_step(
+        &self,
+        config: &Config,
+        step: &LoadPlanStep,
+        protected: &[&str],
+        requested: &str,
+    ) -> Result<(ModelConfig, Vec<String>, Option<LogsAndTailReceiver>)> {
+        let model_config = &config.models[step.index];
         let alias = model_config.alias();
 
-        let spawned = match &model_config.config {
-            ModelTypeConfig::LlamaCpp(LlamaCppModelConfig {
-                hf_repo,
-                port,
+        let to_stop = [alias]
+            .into_iter()
+            .chain(step.unloads.iter().map(String::as_str))
+            .filter(|unload| *unload == alias || !protected.contains(unload))
+            .collect::<Vec<_>>();
+
+        // Previous instances are stopped before spawning so ports are free again
+        let (stopping, evicted) = {
+            let mut loaded_models = self.loaded.lock().await;
+
+            if let Some(busy) = to_stop.iter().find(|stop| {
+                **stop != requested && loaded_models.get(**stop).is_some_and(LoadedModel::is_busy)
+            }) {
+                bail!(
+                    "Model '{busy}' has requests in flight and can't be stopped to load '{requested}'"
+                );
+            }
+
+            let mut evicted = select_group_evictions(
+                &loaded_models,
+                &to_stop,
+                protected,
                 alias,
-                api_key,
-                host,
-                additional_properties,
-            }) => {
-                let mut command = Command::new("llama-server");
-
-                command
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .arg("--alias")
-                    .arg(alias)
-                    .arg("--hf-repo")
-                    .arg(hf_repo)
-                    .arg("--port")
-                    .arg(port.to_string());
-
-                if let Some(api_key) = api_key {
-                    command.arg("--api-key").arg(api_key.expose_ref());
+                &config.get_groups(model_config)?,
+            )?;
+
+            let excluded = to_stop
+                .iter()
+                .copied()
+                .chain(evicted.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+
+            if let Some(budget) = config.budget {
+                evicted.extend(select_evictions(
+                    &loaded_models,
+                    &excluded,
+                    protected,
+                    alias,
+                    model_config.cost.unwrap_or(0),
+                    budget,
+                )?);
+            }
+
+            let stopping = to_stop
+                .iter()
+                .copied()
+                .chain(evicted.iter().map(String::as_str))
+                .filter_map(|alias| loaded_models.remove(alias))
+                .collect::<Vec<_>>();
+
+            (stopping, evicted)
+        };
+
+        for evicted_alias in &evicted {
+            println!("Evicting model '{evicted_alias}' to make room for '{alias}'");
+        }
+
+        for loaded_model in stopping {
+            loaded_model.stop().await;
+        }
+
+        // Ports that were free when probed but taken by the time the server bound them
+        let mut taken_ports = Vec::new();
+
+        loop {
+            // Held until the model is inserted so concurrent loads can't be assigned the same port
+            let mut loaded_models = self.loaded.lock().await;
+
+            let mut model_config = model_config.clone();
+            let mut port_assigned = false;
+            let host = model_config
+                .config
+                .host()
+                .unwrap_or("localhost")
+                .to_string();
+
+            if let Some(port) = model_config.config.port_mut()
+                && port.is_none()
+            {
+                *port = Some(allocate_port(config, &loaded_models, &host, &taken_ports)?);
+                port_assigned = true;
+            }
+
+            let (state_sender, mut state) = watch::channel(ModelState::Starting);
+
+            let health_url = match &model_config.config {
+                ModelTypeConfig::Command(x) => x.health_url.as_deref().unwrap_or("/health"),
+                ModelTypeConfig::LlamaCpp(_) | ModelTypeConfig::External(_) => "/health",
+            };
+
+            let spawned = match launch_command(config, &model_config)? {
+                Some(launch) => {
+                    let supervision = Supervision {
+                        health_url: model_config.url()?.join(health_url)?,
+                        ready_timeout: Duration::from_secs(
+                            model_config
+                                .ready_timeout_secs
+                                .unwrap_or(DEFAULT_READY_TIMEOUT_SECS),
+                        ),
+                        stop_timeout: Duration::from_secs(
+                            model_config
+                                .stop_timeout_secs
+                                .unwrap_or(DEFAULT_STOP_TIMEOUT_SECS),
+                        ),
+                        restart_policy: model_config.restart.clone().unwrap_or_default(),
+                    };
+
+                    Some(Spawned::spawn(launch, supervision, state_sender)?)
                 }
+                None => {
+                    state_sender.send_replace(ModelState::Ready);
 
-                if let Some(host) = host {
-                    command.arg("--host").arg(host);
+                    None
                 }
+            };
 
-                for (key, value) in additional_properties {
-                    if value.as_null().is_some() || value.as_bool().is_some_and(|b| !b) {
-                        continue;
-                    }
+            let logs_and_tail_receiver = if let Some(spawned) = spawned.as_ref() {
+                Some(LogsAndTailReceiver::from_ref(spawned).await)
+            } else {
+                None
+            };
 
-                    command.arg(format!("--{key}"));
+            let now = Utc::now();
 
-                    if value.as_bool().is_none() {
-                        command.arg(serde_json::to_string(&value).unwrap());
-                    }
-                }
+            loaded_models.insert(
+                model_config.alias().to_string(),
+                LoadedModel {
+                    config: model_config.clone(),
+                    port_assigned,
+                    spawned,
+                    state: state.clone(),
+                    loaded_at: now,
+                    last_activity: Arc::new(std::sync::Mutex::new(now)),
+                    idle_timeout_secs: model_config.idle_timeout_secs.or(config.idle_timeout_secs),
+                    concurrency: Arc::new(Concurrency::new(
+                        model_config.max_concurrent,
+                        model_config.max_queue,
+                    )),
+                },
+            );
 
-                command.kill_on_drop(true);
-
-                let mut child = command.spawn()?;
-
-                let mut stdout = child.stdout.take().unwrap();
-
-                let mut stderr = child.stderr.take().unwrap();
-
-                let (sender, mut receiver) = broadcast::channel(1024 * 1024);
-
-                tokio::spawn({
-                    let sender = sender.clone();
-
-                    async move {
-                        let mut buf = [0u8; 1024];
-
-                        loop {
-                            let Ok(num_read) = stdout.read(&mut buf).await else {
-                                break;
-                            };
-
-                            if num_read == 0 {
-                                break;
-                            }
-
-                            if let Ok(string) = String::from_utf8(buf[0..num_read].to_vec()) {
-                                if sender
-                                    .send(Log::StdOut(TimestampedMessage::new(string)))
-                                    .is_err()
-                                {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                });
-
-                tokio::spawn({
-                    let sender = sender.clone();
-
-                    async move {
-                        let mut buf = [0u8; 1024];
-
-                        loop {
-                            let Ok(num_read) = stderr.read(&mut buf).await else {
-                                break;
-                            };
-
-                            if num_read == 0 {
-                                break;
-                            }
-
-                            if let Ok(string) = String::from_utf8(buf[0..num_read].to_vec()) {
-                                if sender
-                                    .send(Log::StdErr(TimestampedMessage::new(string)))
-                                    .is_err()
-                                {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                });
-
-                let logs: Arc<Mutex<Vec<Log>>> = Default::default();
-
-                tokio::spawn({
-                    let logs = logs.clone();
-
-                    async move {
-                        while let Ok(x) = receiver.recv().await {
-                            logs.lock().await.push(x)
-                        }
-                    }
-                });
-
-                Some(Spawned {
-                    io_sender: sender,
-                    _child: child,
-                    logs,
-                })
-            }
-            ModelTypeConfig::External(_) => None,
-        };
+            drop(loaded_models);
 
-        let logs_and_tail_receiver = if let Some(spawned) = spawned.as_ref() {
-            Some(LogsAndTailReceiver::from_ref(spawned).await)
-        } else {
-            None
-        };
+            if let ModelState::Failed { reason } = ModelState::wait_until_started(&mut state).await
+            {
+                let removed = self.loaded.lock().await.remove(alias);
 
-        loaded_models.insert(
-            model_config.alias().to_string(),
-            LoadedModel {
-                config: model_config.clone(),
-                spawned,
-            },
-        );
+                let stderr_tail = match removed.and_then(|m| m.spawned) {
+                    Some(spawned) => spawned.stop_with_stderr_tail(STDERR_TAIL_LINES).await,
+                    None => String::new(),
+                };
+
+                if port_assigned
+                    && taken_ports.len() + 1 < MAX_PORT_ATTEMPTS
+                    && is_bind_failure(&stderr_tail)
+                    && let Some(port) = model_config.config.port()
+                {
+                    println!(
+                        "Port {port} of model '{alias}' was taken, retrying with another port"
+                    );
 
-        if let Some(to_unload) = &model_config.unloads {
-            for alias_or_index in to_unload {
-                let model_config = config.get_model_config(alias_or_index)?;
+                    taken_ports.push(port);
 
-                if model_config.alias() == alias {
                     continue;
                 }
 
-                loaded_models.remove(model_config.alias());
+                bail!("Model '{alias}' failed to start: {reason}\n{stderr_tail}");
             }
+
+            return Ok((model_config, evicted, logs_and_tail_receiver));
         }
+    }
+}
 
-        drop(loaded_models);
+/// Builds the command line a spawned model is started with, `None` for external models.
+pub fn launch_command(
+    config: &Config,
+    model_config: &ModelConfig,
+) -> Result<Option<LaunchCommand>> {
+    let launch = match &model_config.config {
+        ModelTypeConfig::LlamaCpp(LlamaCppModelConfig {
+            source,
+            hf_file,
+            port,
+            alias,
+            api_key,
+            host,
+            binary,
+            env,
+            working_dir,
+            extra_args,
+            negate_false_flags,
+            additional_properties,
+        }) => {
+            let mut args = vec![
+                "--alias".to_string(),
+                alias.clone(),
+                "--port".to_string(),
+                render_port(*port),
+            ];
 
-        if let Some(to_load) = &model_config.loads {
-            for alias_or_index in to_load {
-                if model_config.alias() == alias {
-                    continue;
+            match source {
+                ModelSource::HfRepo(hf_repo) => {
+                    args.extend(["--hf-repo".to_string(), hf_repo.clone()]);
+                }
+                ModelSource::Model(path) => {
+                    args.extend(["--model".to_string(), path.to_string_lossy().to_string()]);
                 }
+                ModelSource::ModelUrl(url) => {
+                    args.extend(["--model-url".to_string(), url.to_string()]);
+                }
+            }
+
+            if let Some(hf_file) = hf_file {
+                args.extend(["--hf-file".to_string(), hf_file.clone()]);
+            }
+
+            if let Some(api_key) = api_key.as_ref().and_then(SecretConfig::secret) {
+                args.extend(["--api-key".to_string(), api_key.expose_clone()]);
+            }
+
+            if let Some(host) = host {
+                args.extend(["--host".to_string(), host.clone()]);
+            }
+
+            args.extend(argv::render_properties(
+                additional_properties,
+                negate_false_flags.as_bool(),
+            ));
+
+            args.extend(extra_args.iter().flatten().cloned());
+
+            LaunchCommand {
+                program: binary
+                    .as_ref()
+                    .or(config.llama_cpp_binary.as_ref())
+                    .cloned()
+                    .unwrap_or("llama-server".to_string()),
+                args,
+                env: resolve_env(alias, env, |value| value.to_string())?,
+                working_dir: working_dir.clone(),
+            }
+        }
+        ModelTypeConfig::Command(x) => {
+            let (program, args) = x
+                .argv
+                .split_first()
+                .ok_or(anyhow!("Model '{}' has an empty argv", x.alias))?;
 
-                self.load(config, alias_or_index).await?;
+            LaunchCommand {
+                program: x.render(program),
+                args: args.iter().map(|arg| x.render(arg)).collect(),
+                env: resolve_env(&x.alias, &x.env, |value| x.render(value))?,
+                working_dir: x.working_dir.clone(),
             }
         }
+        ModelTypeConfig::External(_) => return Ok(None),
+    };
+
+    Ok(Some(launch))
+}
+
+/// Whether `loaded_config`, launched with `previous`, would be launched the same way as
+/// `model_config` with `config`
+fn is_unchanged(
+    previous: &Config,
+    loaded_config: &ModelConfig,
+    config: &Config,
+    model_config: &ModelConfig,
+) -> bool {
+    // Secrets are redacted when serialized
+    serde_json::to_value(loaded_config).is_ok_and(|loaded_value| {
+        serde_json::to_value(model_config).is_ok_and(|value| value == loaded_value)
+    }) && loaded_config.api_key().map(Secret::expose_ref)
+        == model_config.api_key().map(Secret::expose_ref)
+        && launch_command(previous, loaded_config).ok() == launch_command(config, model_config).ok()
+}
+
+fn resolve_env(
+    alias: &str,
+    env: &Option<HashMap<String, SecretConfig>>,
+    render: impl Fn(&str) -> String,
+) -> Result<HashMap<String, String>> {
+    env.iter()
+        .flatten()
+        .map(|(key, value)| {
+            let value = value.secret().ok_or(anyhow!(
+                "Environment variable '{key}' of model '{alias}' could not be read"
+            ))?;
+
+            Ok((key.clone(), render(value.expose_ref())))
+        })
+        .collect()
+}
+
+/// Picks the first port in the config-wide port range that isn't configured for or used by another
+/// model, hasn't turned out to be `taken` and can currently be bound on `host`.
+fn allocate_port(
+    config: &Config,
+    loaded_models: &HashMap<String, LoadedModel>,
+    host: &str,
+    taken: &[u16],
+) -> Result<u16> {
+    let PortRange { start, end } = config.port_range.clone().unwrap_or_default();
+
+    let reserved = config
+        .models
+        .iter()
+        .chain(loaded_models.values().map(|m| &m.config))
+        .filter_map(|model_config| model_config.config.port())
+        .collect::<HashSet<_>>();
+
+    (start..=end)
+        .find(|port| {
+            !reserved.contains(port)
+                && !taken.contains(port)
+                && TcpListener::bind((host, *port)).is_ok()
+        })
+        .ok_or(anyhow!("No free port in range {start}-{end}"))
+}
+
+/// Messages servers log when their port is already in use, e.g. llama-server's "couldn't bind
+/// HTTP server socket" or uvicorn's "error while attempting to bind on address"
+const BIND_FAILURE_MESSAGES: [&str; 5] = [
+    "address already in use",
+    "couldn't bind",
+    "could not bind",
+    "failed to bind",
+    "attempting to bind",
+];
+
+/// Whether the output of a server that failed to start says its port was already in use
+fn is_bind_failure(output: &str) -> bool {
+    let output = output.to_lowercase();
+
+    BIND_FAILURE_MESSAGES
+        .iter()
+        .any(|message| output.contains(message))
+}
+
+/// Picks the least recently used unpinned members without requests in flight of each group to
+/// unload until `alias` fits within the group's `max-loaded`. Models in `excluded` are about to be
+/// unloaded anyway.
+fn select_group_evictions(
+    loaded_models: &HashMap<String, LoadedModel>,
+    excluded: &[&str],
+    protected: &[&str],
+    alias: &str,
+    groups: &[(&str, &GroupConfig, Vec<&str>)],
+) -> Result<Vec<String>> {
+    let mut evicted = Vec::<String>::new();
+
+    for (name, group, members) in groups {
+        let mut loaded_members = loaded_models
+            .iter()
+            .filter(|(loaded_alias, _)| {
+                members.contains(&loaded_alias.as_str())
+                    && !excluded.contains(&loaded_alias.as_str())
+                    && !evicted.contains(loaded_alias)
+            })
+            .collect::<Vec<_>>();
+
+        let mut excess = (loaded_members.len() + 1).saturating_sub(group.max_loaded());
+
+        loaded_members.retain(|(member_alias, m)| {
+            !m.config.pinned.as_bool()
+                && !m.is_busy()
+                && !protected.contains(&member_alias.as_str())
+        });
+        loaded_members.sort_by_key(|(_, m)| *m.last_activity.lock().unwrap());
+
+        if excess > loaded_members.len() {
+            bail!(
+                "Model '{alias}' does not fit in group '{name}' which allows {} loaded member(s) and has pinned, busy or dependent members loaded",
+                group.max_loaded()
+            );
+        }
+
+        for (member_alias, _) in loaded_members {
+            if excess == 0 {
+                break;
+            }
+
+            evicted.push(member_alias.clone());
+            excess -= 1;
+        }
+    }
+
+    Ok(evicted)
+}
+
+/// Picks the least recently used unpinned models without requests in flight to unload until `cost`
+/// fits within `budget`. Models in `excluded` are about to be unloaded anyway and are not counted.
+fn select_evictions(
+    loaded_models: &HashMap<String, LoadedModel>,
+    excluded: &[&str],
+    protected: &[&str],
+    alias: &str,
+    cost: u64,
+    budget: u64,
+) -> Result<Vec<String>> {
+    let mut candidates = loaded_models
+        .iter()
+        .filter(|(loaded_alias, _)| !excluded.contains(&loaded_alias.as_str()))
+        .collect::<Vec<_>>();
+
+    let mut used = candidates.iter().map(|(_, m)| m.cost()).sum::<u64>();
+
+    candidates.retain(|(candidate_alias, m)| {
+        !m.config.pinned.as_bool() && !m.is_busy() && !protected.contains(&candidate_alias.as_str())
+    });
+    candidates.sort_by_key(|(_, m)| *m.last_activity.lock().unwrap());
+
+    let mut candidates = candidates.into_iter();
+    let mut evicted = Vec::new();
+
+    while used + cost > budget {
+        let Some((candidate_alias, candidate)) = candidates.next() else {
+            bail!(
+                "Model '{alias}' with cost {cost} does not fit within the budget of {budget} ({used} used by pinned, busy or dependent models)"
+            );
+        };
+
+        used -= candidate.cost();
+        evicted.push(candidate_alias.clone());
+    }
+
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn loaded_model(alias: &str, cost: u64, pinned: bool, idle_secs: i64) -> LoadedModel {
+        let config = serde_json::from_value(json!({
+            "type": "command",
+            "cost": cost,
+            "pinned": pinned,
+            "config": { "alias": alias, "argv": ["server"] }
+        }))
+        .unwrap();
+
+        LoadedModel {
+            config,
+            port_assigned: false,
+            spawned: None,
+            state: watch::channel(ModelState::Ready).1,
+            loaded_at: Utc::now(),
+            last_activity: Arc::new(std::sync::Mutex::new(
+                Utc::now() - TimeDelta::seconds(idle_secs),
+            )),
+            idle_timeout_secs: None,
+            concurrency: Arc::new(Concurrency::new(None, None)),
+        }
+    }
+
+    fn loaded_models(models: Vec<LoadedModel>) -> HashMap<String, LoadedModel> {
+        models
+            .into_iter()
+            .map(|m| (m.config.alias().to_string(), m))
+            .collect()
+    }
+
+    #[test]
+    fn least_recently_used_models_are_evicted_until_the_model_fits() {
+        let loaded = loaded_models(vec![
+            loaded_model("recent", 4, false, 10),
+            loaded_model("old", 4, false, 30),
+            loaded_model("older", 4, false, 20),
+        ]);
+
+        assert_eq!(
+            select_evictions(&loaded, &[], &[], "new", 6, 12).unwrap(),
+            ["old", "older"]
+        );
+    }
+
+    #[test]
+    fn nothing_is_evicted_when_the_model_fits() {
+        let loaded = loaded_models(vec![loaded_model("a", 4, false, 10)]);
+
+        assert!(
+            select_evictions(&loaded, &[], &[], "new", 4, 8)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn excluded_models_are_not_counted() {
+        let loaded = loaded_models(vec![
+            loaded_model("stopping", 8, false, 10),
+            loaded_model("other", 2, false, 20),
+        ]);
+
+        assert!(
+            select_evictions(&loaded, &["stopping"], &[], "new", 6, 8)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn pinned_and_protected_models_are_never_evicted() {
+        let loaded = loaded_models(vec![
+            loaded_model("pinned", 4, true, 30),
+            loaded_model("dependency", 4, false, 20),
+            loaded_model("other", 4, false, 10),
+        ]);
+
+        assert_eq!(
+            select_evictions(&loaded, &[], &["dependency"], "new", 4, 12).unwrap(),
+            ["other"]
+        );
+        assert!(select_evictions(&loaded, &[], &["dependency"], "new", 8, 12).is_err());
+    }
+
+    #[tokio::test]
+    async fn busy_models_are_never_evicted() {
+        let loaded = loaded_models(vec![
+            loaded_model("busy", 4, false, 30),
+            loaded_model("idle", 4, false, 10),
+        ]);
+
+        let _in_flight = loaded["busy"]
+            .concurrency
+            .clone()
+            .acquire("busy")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            select_evictions(&loaded, &[], &[], "new", 4, 8).unwrap(),
+            ["idle"]
+        );
+        assert!(select_evictions(&loaded, &[], &[], "new", 8, 8).is_err());
+    }
+
+    fn group(max_loaded: Option<usize>) -> GroupConfig {
+        GroupConfig {
+            members: Vec::new(),
+            max_loaded,
+        }
+    }
+
+    #[test]
+    fn group_members_are_exclusive_by_default() {
+        let loaded = loaded_models(vec![
+            loaded_model("a", 0, false, 10),
+            loaded_model("other", 0, false, 30),
+        ]);
+        let group = group(None);
+
+        assert_eq!(
+            select_group_evictions(&loaded, &[], &[], "b", &[("g", &group, vec!["a", "b"])])
+                .unwrap(),
+            ["a"]
+        );
+    }
+
+    #[test]
+    fn least_recently_used_group_members_are_evicted() {
+        let loaded = loaded_models(vec![
+            loaded_model("a", 0, false, 30),
+            loaded_model("b", 0, false, 10),
+            loaded_model("c", 0, false, 20),
+        ]);
+        let group = group(Some(2));
+
+        assert_eq!(
+            select_group_evictions(
+                &loaded,
+                &[],
+                &[],
+                "d",
+                &[("g", &group, vec!["a", "b", "c", "d"])]
+            )
+            .unwrap(),
+            ["a", "c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_and_busy_group_members_are_kept() {
+        let loaded = loaded_models(vec![
+            loaded_model("pinned", 0, true, 30),
+            loaded_model("busy", 0, false, 20),
+        ]);
+        let group = group(Some(2));
+
+        let _in_flight = loaded["busy"]
+            .concurrency
+            .clone()
+            .acquire("busy")
+            .await
+            .unwrap();
+
+        assert!(
+            select_group_evictions(
+                &loaded,
+                &[],
+                &[],
+                "new",
+                &[("g", &group, vec!["pinned", "busy", "new"])]
+            )
+            .is_err()
+        );
+    }
+
+    fn external(alias: &str, id: &str, loads: Value) -> Value {
+        json!({
+            "type": "external",
+            "loads": loads,
+            "config": { "base-url": "http://localhost:1", "api-key": "key", "id": id, "alias": alias }
+        })
+    }
+
+    fn config(models: Vec<Value>) -> Config {
+        serde_json::from_value(json!({ "models": models })).unwrap()
+    }
+
+    async fn loaded_at(models: &Models, alias: &str) -> DateTime<Utc> {
+        models.loaded.lock().await[alias].loaded_at
+    }
+
+    #[tokio::test]
+    async fn running_dependencies_are_not_restarted() {
+        let config = config(vec![
+            external("app", "app", json!(["dependency"])),
+            external("dependency", "dependency", json!(null)),
+        ]);
+        let models = Models::default();
+
+        models.load(&config, "app").await.unwrap();
+        let dependency_loaded_at = loaded_at(&models, "dependency").await;
+        let app_loaded_at = loaded_at(&models, "app").await;
+
+        models.load(&config, "app").await.unwrap();
+
+        assert_eq!(loaded_at(&models, "dependency").await, dependency_loaded_at);
+        assert!(loaded_at(&models, "app").await > app_loaded_at);
+    }
+
+    #[tokio::test]
+    async fn busy_dependencies_are_not_stopped() {
+        let models = Models::default();
+
+        models
+            .load(
+                &config(vec![external("dependency", "old", json!(null))]),
+                "dependency",
+            )
+            .await
+            .unwrap();
+
+        let _guard = models.begin_request("dependency").await.unwrap();
+
+        let changed = config(vec![
+            external("app", "app", json!(["dependency"])),
+            external("dependency", "new", json!(null)),
+        ]);
+
+        assert!(models.load(&changed, "app").await.is_err());
+        assert_eq!(models.loaded.lock().await["dependency"].config.id(), "old");
+    }
+
+    /// Answers every request with 200 so spawned models can be made ready without a real server
+    fn health_server() -> u16 {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = std::io::Read::read(&mut stream, &mut [0; 1024]);
+                let _ = std::io::Write::write_all(
+                    &mut stream,
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                );
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_unchanged_dependencies_of_changed_models_running() {
+        let port = health_server();
+        let dependency = json!({
+            "type": "command",
+            "config": {
+                "alias": "dependency",
+                "argv": ["sleep", "600"],
+                "port": port,
+                "health-url": format!("http://127.0.0.1:{port}/health")
+            }
+        });
+
+        let previous = config(vec![
+            external("app", "old", json!(["dependency"])),
+            dependency.clone(),
+        ]);
+        let changed = config(vec![
+            external("app", "new", json!(["dependency"])),
+            dependency,
+        ]);
+        let models = Models::default();
+
+        models.load(&previous, "app").await.unwrap();
+        let dependency_loaded_at = loaded_at(&models, "dependency").await;
+
+        let report = models.reload(&previous, &changed).await.unwrap();
+
+        assert_eq!(report.restarted, ["app"]);
+        assert_eq!(report.unchanged, ["dependency"]);
+        assert_eq!(loaded_at(&models, "dependency").await, dependency_loaded_at);
+        assert!(matches!(
+            models.loaded.lock().await["dependency"].status().state,
+            ModelState::Ready
+        ));
+
+        models.unload("app").await.unwrap();
+        models.unload("dependency").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn models_that_never_start_fail_despite_unlimited_restarts() {
+        let config = config(vec![json!({
+            "type": "command",
+            "restart": { "policy": "always" },
+            "config": { "alias": "broken", "argv": ["sh", "-c", "exit 1"], "port": 1 }
+        })]);
+        let models = Models::default();
+
+        let result = tokio::time::timeout(Duration::from_secs(10), models.load(&config, "broken"))
+            .await
+            .expect("Load fails without restarting");
+
+        assert!(result.is_err());
+        assert!(models.loaded.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_waiting_on_an_unloaded_model_are_not_started() {
+        let mut model = external("m", "m", json!(null));
+        model["max-concurrent"] = json!(1);
+        let config = config(vec![model]);
+        let models = Models::default();
+
+        assert!(models.begin_request("m").await.unwrap().is_none());
+
+        models.load(&config, "m").await.unwrap();
+
+        let (model_config, guard) = models.begin_request("m").await.unwrap().unwrap();
+        assert_eq!(model_config.alias(), "m");
+
+        let waiting = tokio::spawn({
+            let models = models.clone();
+
+            async move { models.begin_request("m").await.unwrap().is_none() }
+        });
+
+        tokio::task::yield_now().await;
+        models.loaded.lock().await.remove("m");
+        drop(guard);
+
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_models_are_replaced_on_demand() {
+        let config = config(vec![external("m", "m", json!(null))]);
+        let models = Models::default();
+
+        let mut failed = loaded_model("m", 0, false, 0);
+        failed.state = watch::channel(ModelState::Failed {
+            reason: "Crashed".into(),
+        })
+        .1;
+        models.loaded.lock().await.insert("m".into(), failed);
+
+        models.load_on_demand(&config, "m").await.unwrap();
+
+        assert!(matches!(
+            models.loaded.lock().await["m"].status().state,
+            ModelState::Ready
+        ));
+    }
 
-        Ok((model_config.clone(), logs_and_tail_receiver))
+    #[test]
+    fn bind_failures_are_recognized_by_their_messages() {
+        assert!(is_bind_failure(
+            "main: couldn't bind HTTP server socket, hostname: 127.0.0.1, port: 8100"
+        ));
+        assert!(is_bind_failure(
+            "OSError: [Errno 98] Address already in use"
+        ));
+        assert!(!is_bind_failure(
+            "binding to model bind-large, args: --bind 0.0.0.0"
+        ));
+        assert!(!is_bind_failure("error: failed to load model"));
     }
 }
@@ -0,0 +1,138 @@
+use serde_json::{Map, Value};
+
+/// Renders llama-server flags from the additional properties of a model config.
+///
+/// - `null` is skipped
+/// - `true` renders `--key`, `false` is skipped or renders `--no-key` if `negate_false` is set
+/// - Strings and numbers render `--key value` without JSON quoting
+/// - Arrays repeat the flag for every element, e.g. multiple `--lora`
+/// - Objects repeat the flag with a `name=value` pair per entry, e.g. `--override-kv`
+pub fn render_properties(properties: &Map<String, Value>, negate_false: bool) -> Vec<String> {
+    let mut args = Vec::new();
+
+    for (key, value) in properties {
+        render_property(key, value, negate_false, &mut args);
+    }
+
+    args
+}
+
+fn render_property(key: &str, value: &Value, negate_false: bool, args: &mut Vec<String>) {
+    match value {
+        Value::Null => {}
+        Value::Bool(true) => args.push(format!("--{key}")),
+        Value::Bool(false) if negate_false => args.push(format!("--no-{key}")),
+        Value::Bool(false) => {}
+        Value::Number(_) | Value::String(_) => {
+            args.extend([format!("--{key}"), render_value(value)]);
+        }
+        Value::Array(values) => {
+            for value in values {
+                render_property(key, value, negate_false, args);
+            }
+        }
+        Value::Object(entries) => {
+            for (name, value) in entries {
+                args.extend([
+                    format!("--{key}"),
+                    format!("{name}={}", render_value(value)),
+                ]);
+            }
+        }
+    }
+}
+
+/// Strings are used verbatim, anything else as JSON
+fn render_value(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn render(value: Value) -> Vec<String> {
+        render_properties(value.as_object().unwrap(), false)
+    }
+
+    #[test]
+    fn strings_are_not_quoted() {
+        assert_eq!(
+            render(json!({ "chat-template": "chatml" })),
+            ["--chat-template", "chatml"]
+        );
+    }
+
+    #[test]
+    fn numbers_are_rendered_as_is() {
+        assert_eq!(
+            render(json!({ "ctx-size": 4096, "temp": 0.7 })),
+            ["--ctx-size", "4096", "--temp", "0.7"]
+        );
+    }
+
+    #[test]
+    fn false_is_skipped() {
+        assert_eq!(
+            render(json!({ "flash-attn": true, "mmap": false })),
+            ["--flash-attn"]
+        );
+    }
+
+    #[test]
+    fn false_is_negated_when_opted_in() {
+        assert_eq!(
+            render_properties(
+                json!({ "flash-attn": true, "mmap": false })
+                    .as_object()
+                    .unwrap(),
+                true
+            ),
+            ["--flash-attn", "--no-mmap"]
+        );
+    }
+
+    #[test]
+    fn null_is_skipped() {
+        assert!(render(json!({ "ctx-size": null })).is_empty());
+    }
+
+    #[test]
+    fn arrays_repeat_the_flag() {
+        assert_eq!(
+            render(json!({ "lora": ["a.gguf", "b.gguf"] })),
+            ["--lora", "a.gguf", "--lora", "b.gguf"]
+        );
+    }
+
+    #[test]
+    fn objects_render_key_value_pairs() {
+        assert_eq!(
+            render(json!({
+                "override-kv": {
+                    "tokenizer.ggml.add_bos_token": "bool:false",
+                    "llama.context_length": "int:8192"
+                }
+            })),
+            [
+                "--override-kv",
+                "llama.context_length=int:8192",
+                "--override-kv",
+                "tokenizer.ggml.add_bos_token=bool:false"
+            ]
+        );
+    }
+
+    #[test]
+    fn object_values_that_are_not_strings_are_json() {
+        assert_eq!(
+            render(json!({ "override-kv": { "a": 1, "b": true } })),
+            ["--override-kv", "a=1", "--override-kv", "b=true"]
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, thiserror::Error)]
+#[error("{queued} requests already queued for model '{alias}'")]
+pub struct QueueFull {
+    pub alias: String,
+    pub queued: usize,
+}
+
+/// Limits concurrent requests to a model and tracks queued and in-flight counts.
+pub struct Concurrency {
+    semaphore: Option<Arc<Semaphore>>,
+    max_queue: Option<usize>,
+    queued: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl Concurrency {
+    pub fn new(max_concurrent: Option<usize>, max_queue: Option<usize>) -> Self {
+        Self {
+            semaphore: max_concurrent.map(|permits| Arc::new(Semaphore::new(permits))),
+            max_queue,
+            queued: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Waits for a free slot, failing right away if the queue is full.
+    pub async fn acquire(self: Arc<Self>, alias: &str) -> Result<InFlight, QueueFull> {
+        let permit = match &self.semaphore {
+            None => None,
+            Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    let queued = self.queued.fetch_add(1, Ordering::Relaxed);
+                    let _queued = Queued(&self.queued);
+
+                    if self.max_queue.is_some_and(|max_queue| queued >= max_queue) {
+                        return Err(QueueFull {
+                            alias: alias.to_string(),
+                            queued,
+                        });
+                    }
+
+                    Some(
+                        semaphore
+                            .clone()
+                            .acquire_owned()
+                            .await
+                            .expect("Semaphore is never closed"),
+                    )
+                }
+            },
+        };
+
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        Ok(InFlight {
+            concurrency: self,
+            _permit: permit,
+        })
+    }
+}
+
+/// Decrements the queued count when the wait ends, including when the request is cancelled.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct InFlight {
+    concurrency: Arc<Concurrency>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.concurrency.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use tokio::sync::broadcast;
+
+use crate::models::spawned::Spawned;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimestampedMessage {
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
+}
+
+impl TimestampedMessage {
+    pub fn new(message: impl Display) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "stream")]
+pub enum Log {
+    StdOut(TimestampedMessage),
+    StdErr(TimestampedMessage),
+    Herder(TimestampedMessage),
+}
+
+impl Display for Log {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (stream, TimestampedMessage { timestamp, message }) = match &self {
+            Log::StdOut(timestamped_message) => ("StdOut", timestamped_message),
+            Log::StdErr(timestamped_message) => ("StdErr", timestamped_message),
+            Log::Herder(timestamped_message) => ("Herder", timestamped_message),
+        };
+
+        f.write_fmt(format_args!("{stream} - {timestamp} - {message}"))
+    }
+}
+
+pub struct LogsAndTailReceiver {
+    pub tail_receiver: broadcast::Receiver<Log>,
+    pub logs: Vec<Log>,
+}
+
+impl LogsAndTailReceiver {
+    pub(super) async fn from_ref(spawned: &Spawned) -> Self {
+        LogsAndTailReceiver {
+            logs: spawned.logs.lock().await.clone(),
+            tail_receiver: spawned.io_sender.subscribe(),
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::{Child, Command},
+    sync::{Mutex, broadcast, oneshot, watch},
+    task::JoinHandle,
+};
+use url::Url;
+
+use crate::{
+    config::RestartPolicy,
+    models::{Log, ModelState, TimestampedMessage},
+};
+
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long output of a stopped child is waited for, in case a grandchild keeps its pipes open
+const LOG_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// A process that stayed ready this long has recovered, so its restarts count from zero again
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaunchCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Added to the inherited environment
+    pub env: HashMap<String, String>,
+    pub working_dir: Option<PathBuf>,
+}
+
+impl LaunchCommand {
+    fn spawn(&self, sender: &broadcast::Sender<Log>) -> std::io::Result<Child> {
+        let mut command = Command::new(&self.program);
+
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
+
+        let mut child = command
+            .args(&self.args)
+            .envs(&self.env)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        tokio::spawn(pipe_logs(
+            child.stdout.take().unwrap(),
+            sender.clone(),
+            Log::StdOut,
+        ));
+
+        tokio::spawn(pipe_logs(
+            child.stderr.take().unwrap(),
+            sender.clone(),
+            Log::StdErr,
+        ));
+
+        Ok(child)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Supervision {
+    pub health_url: Url,
+    pub ready_timeout: Duration,
+    pub stop_timeout: Duration,
+    pub restart_policy: RestartPolicy,
+}
+
+pub struct Spawned {
+    pub io_sender: broadcast::Sender<Log>,
+    pub logs: Arc<Mutex<Vec<Log>>>,
+    stop: oneshot::Sender<()>, // The child is terminated when this is sent or dropped
+    stopped: oneshot::Receiver<()>,
+    /// Collects into `logs` until every sender of the output is dropped
+    collector: JoinHandle<()>,
+}
+
+impl Spawned {
+    /// Spawns the command along with a supervisor task that publishes readiness and exits
+    /// through `state` and restarts the child according to the restart policy.
+    pub fn spawn(
+        launch: LaunchCommand,
+        supervision: Supervision,
+        state: watch::Sender<ModelState>,
+    ) -> Result<Self> {
+        let (sender, mut receiver) = broadcast::channel(1024 * 1024);
+
+        let child = launch.spawn(&sender)?;
+
+        let logs: Arc<Mutex<Vec<Log>>> = Default::default();
+
+        let collector = tokio::spawn({
+            let logs = logs.clone();
+
+            async move {
+                while let Ok(x) = receiver.recv().await {
+                    logs.lock().await.push(x)
+                }
+            }
+        });
+
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let (stopped_sender, stopped_receiver) = oneshot::channel();
+
+        tokio::spawn(supervise(
+            child,
+            launch,
+            supervision,
+            state,
+            sender.clone(),
+            stop_receiver,
+            stopped_sender,
+        ));
+
+        Ok(Spawned {
+            io_sender: sender,
+            logs,
+            stop: stop_sender,
+            stopped: stopped_receiver,
+            collector,
+        })
+    }
+
+    /// Terminates the child gracefully and waits until it has exited.
+    pub async fn stop(self) {
+        drop(self.stop);
+
+        let _ = self.stopped.await;
+    }
+
+    /// Terminates the child like `stop` and returns the last `line_count` lines it wrote to stderr,
+    /// once its output has been collected.
+    pub async fn stop_with_stderr_tail(self, line_count: usize) -> String {
+        let Spawned {
+            io_sender,
+            logs,
+            stop,
+            stopped,
+            collector,
+        } = self;
+
+        drop(stop);
+
+        let _ = stopped.await;
+
+        drop(io_sender);
+
+        let _ = tokio::time::timeout(LOG_DRAIN_TIMEOUT, collector).await;
+
+        let stderr = logs
+            .lock()
+            .await
+            .iter()
+            .filter_map(|log| match log {
+                Log::StdErr(timestamped_message) => Some(timestamped_message.message.clone()),
+                Log::StdOut(_) | Log::Herder(_) => None,
+            })
+            .collect::<String>();
+
+        let lines = stderr.lines().collect::<Vec<_>>();
+
+        lines[lines.len().saturating_sub(line_count)..].join("\n")
+    }
+}
+
+async fn pipe_logs(
+    mut reader: impl AsyncRead + Unpin,
+    sender: broadcast::Sender<Log>,
+    to_log: fn(TimestampedMessage) -> Log,
+) {
+    let mut buf = [0u8; 1024];
+
+    while let Ok(num_read) = reader.read(&mut buf).await
+        && num_read != 0
+    {
+        if let Ok(string) = String::from_utf8(buf[0..num_read].to_vec())
+            && sender
+                .send(to_log(TimestampedMessage::new(string)))
+                .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn wait_for_health(health_url: &Url) {
+    let client = reqwest::Client::new();
+
+    loop {
+        if let Ok(response) = client.get(health_url.clone()).send().await
+            && response.status().is_success()
+        {
+            return;
+        }
+
+        tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+    }
+}
+
+enum Exit {
+    Exited(std::io::Result<ExitStatus>),
+    NotReady(Duration),
+}
+
+impl Exit {
+    fn is_success(&self) -> bool {
+        matches!(self, Exit::Exited(Ok(status)) if status.success())
+    }
+
+    fn reason(&self) -> String {
+        match self {
+            Exit::Exited(Ok(status)) => format!("Process exited ({status})"),
+            Exit::Exited(Err(err)) => format!("Failed waiting for process: {err}"),
+            Exit::NotReady(ready_timeout) => {
+                format!("Not ready after {}s", ready_timeout.as_secs())
+            }
+        }
+    }
+}
+
+/// Marks the model as ready once healthy, recording when in `ready_at`, and runs until the child
+/// exits.
+async fn run(
+    child: &mut Child,
+    supervision: &Supervision,
+    state: &watch::Sender<ModelState>,
+    ready_at: &mut Option<Instant>,
+) -> Exit {
+    tokio::select! {
+        result = tokio::time::timeout(supervision.ready_timeout, wait_for_health(&supervision.health_url)) => {
+            if result.is_err() {
+                let _ = terminate(child, supervision.stop_timeout).await;
+
+                return Exit::NotReady(supervision.ready_timeout);
+            }
+
+            state.send_replace(ModelState::Ready);
+            *ready_at = Some(Instant::now());
+        }
+        status = child.wait() => {
+            return Exit::Exited(status);
+        }
+    }
+
+    Exit::Exited(child.wait().await)
+}
+
+/// Sends SIGTERM and waits up to `stop_timeout` for the child to exit before killing it. Also
+/// kills it right away if SIGTERM can't be sent.
+async fn terminate(child: &mut Child, stop_timeout: Duration) -> std::io::Result<ExitStatus> {
+    let Some(pid) = child.id() else {
+        return child.wait().await;
+    };
+
+    // SAFETY: The pid belongs to the child, which can't be reused before the child is waited on
+    let signaled = unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0;
+
+    if signaled && let Ok(status) = tokio::time::timeout(stop_timeout, child.wait()).await {
+        return status;
+    }
+
+    // Fails only if the child has exited already, which waiting picks up
+    let _ = child.start_kill();
+
+    child.wait().await
+}
+
+async fn supervise(
+    mut child: Child,
+    launch: LaunchCommand,
+    supervision: Supervision,
+    state: watch::Sender<ModelState>,
+    sender: broadcast::Sender<Log>,
+    mut stop: oneshot::Receiver<()>,
+    _stopped: oneshot::Sender<()>, // Dropped when supervision ends
+) {
+    let log = |message: String| {
+        let _ = sender.send(Log::Herder(TimestampedMessage::new(message)));
+    };
+
+    let mut retries = 0;
+    // Restarts only apply once the model has been ready, so a model that can't start fails its load
+    let mut been_ready = false;
+
+    loop {
+        let mut ready_at = None;
+
+        let exit = tokio::select! {
+            exit = run(&mut child, &supervision, &state, &mut ready_at) => exit,
+            _ = &mut stop => {
+                let status = terminate(&mut child, supervision.stop_timeout).await;
+
+                log(format!("Stopped: {}", Exit::Exited(status).reason()));
+
+                return;
+            }
+        };
+
+        let reason = exit.reason();
+
+        log(reason.clone());
+
+        if ready_at.is_some_and(|ready_at| ready_at.elapsed() >= STABLE_UPTIME) {
+            retries = 0;
+        }
+
+        been_ready |= ready_at.is_some();
+
+        let Some(delay) = supervision
+            .restart_policy
+            .next_delay(exit.is_success(), retries)
+            .filter(|_| been_ready)
+        else {
+            let reason = if retries == 0 {
+                reason
+            } else {
+                format!("{reason}, gave up after {retries} restart(s)")
+            };
+
+            state.send_replace(ModelState::Failed { reason });
+
+            return;
+        };
+
+        retries += 1;
+
+        state.send_replace(ModelState::Starting);
+
+        log(format!(
+            "Restarting in {}s (attempt {retries})",
+            delay.as_secs()
+        ));
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = &mut stop => return,
+        }
+
+        child = match launch.spawn(&sender) {
+            Ok(child) => child,
+            Err(err) => {
+                let reason = format!("Failed to restart process: {err}");
+
+                log(reason.clone());
+
+                state.send_replace(ModelState::Failed { reason });
+
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn children_ignoring_sigterm_are_killed() {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; sleep 60"])
+            .spawn()
+            .unwrap();
+
+        // Gives the shell time to install the trap
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let status = terminate(&mut child, Duration::from_millis(200))
+            .await
+            .unwrap();
+
+        assert!(!status.success());
+        assert!(child.try_wait().unwrap().is_some());
+    }
+}
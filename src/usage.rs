@@ -0,0 +1,362 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Directory in the state directory with a file of records per UTC day
+const STORE_DIR_NAME: &str = "usage";
+/// Most records written at once by the background writer
+const WRITE_BATCH_SIZE: usize = 256;
+
+/// Token counts reported in the `usage` of an OpenAI-compatible response
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        if self.total_tokens > 0 {
+            self.total_tokens
+        } else {
+            self.prompt_tokens + self.completion_tokens
+        }
+    }
+
+    /// The `usage` object of a response body or stream event, if it has one
+    pub fn from_value(value: &Value) -> Option<Usage> {
+        value
+            .get("usage")
+            .filter(|usage| usage.is_object())
+            .and_then(|usage| serde_json::from_value(usage.clone()).ok())
+    }
+}
+
+/// Usage of a single proxied request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Utc>,
+    pub alias: String,
+    /// Base url the request was proxied to
+    pub upstream: String,
+    /// Name of the authenticated client, `None` when no `clients` are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: &Usage) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total();
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UsageReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+    pub total: UsageTotals,
+    pub by_alias: BTreeMap<String, UsageTotals>,
+    pub by_upstream: BTreeMap<String, UsageTotals>,
+    /// Only requests of authenticated clients
+    pub by_client: BTreeMap<String, UsageTotals>,
+}
+
+enum StoreMessage {
+    Record(UsageRecord),
+    /// Acknowledged once every record sent before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Usage records appended to a JSON lines file per UTC day in the state directory by a background
+/// writer, so requests never wait on the disk.
+#[derive(Clone)]
+pub struct UsageStore {
+    dir: PathBuf,
+    sender: mpsc::UnboundedSender<StoreMessage>,
+}
+
+impl UsageStore {
+    /// Spawns the writer, which needs a tokio runtime.
+    pub fn new(state_dir: &Path) -> Self {
+        let dir = state_dir.join(STORE_DIR_NAME);
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(write_records(dir.clone(), receiver));
+
+        Self { dir, sender }
+    }
+
+    pub fn record(&self, record: UsageRecord) {
+        // Only fails once the writer is gone during shutdown
+        let _ = self.sender.send(StoreMessage::Record(record));
+    }
+
+    /// Waits until every record sent so far is written.
+    pub async fn flush(&self) {
+        let (sender, receiver) = oneshot::channel();
+
+        if self.sender.send(StoreMessage::Flush(sender)).is_ok() {
+            let _ = receiver.await;
+        }
+    }
+
+    /// Sums the records within `since..until`, either bound being open when `None`. Only the
+    /// files of days in range are read, line by line.
+    pub async fn report(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<UsageReport> {
+        let dir = self.dir.clone();
+
+        tokio::task::spawn_blocking(move || read_report(&dir, since, until)).await?
+    }
+}
+
+async fn write_records(dir: PathBuf, mut receiver: mpsc::UnboundedReceiver<StoreMessage>) {
+    let mut messages = Vec::new();
+
+    while receiver.recv_many(&mut messages, WRITE_BATCH_SIZE).await > 0 {
+        let mut records = Vec::new();
+        let mut flushes = Vec::new();
+
+        for message in messages.drain(..) {
+            match message {
+                StoreMessage::Record(record) => records.push(record),
+                StoreMessage::Flush(flushed) => flushes.push(flushed),
+            }
+        }
+
+        if !records.is_empty() {
+            let write_dir = dir.clone();
+
+            match tokio::task::spawn_blocking(move || append_records(&write_dir, &records)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => eprintln!("Failed to record usage to {dir:?}: {err:#}"),
+                Err(err) => eprintln!("Failed to record usage to {dir:?}: {err}"),
+            }
+        }
+
+        for flushed in flushes {
+            let _ = flushed.send(());
+        }
+    }
+}
+
+fn day_file_name(date: NaiveDate) -> String {
+    format!("{}.jsonl", date.format("%Y-%m-%d"))
+}
+
+fn append_records(dir: &Path, records: &[UsageRecord]) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut lines = BTreeMap::<NaiveDate, Vec<u8>>::new();
+
+    for record in records {
+        let line = lines.entry(record.timestamp.date_naive()).or_default();
+
+        serde_json::to_writer(&mut *line, record)?;
+        line.push(b'\n');
+    }
+
+    for (date, lines) in lines {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(day_file_name(date)))?
+            .write_all(&lines)?;
+    }
+
+    Ok(())
+}
+
+fn read_report(
+    dir: &Path,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> Result<UsageReport> {
+    let mut report = UsageReport {
+        since,
+        until,
+        ..Default::default()
+    };
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(report),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {dir:?}")),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+
+        let Some(date) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        else {
+            continue;
+        };
+
+        if since.is_some_and(|since| date < since.date_naive())
+            || until.is_some_and(|until| date > until.date_naive())
+        {
+            continue;
+        }
+
+        let file = File::open(&path).with_context(|| format!("Failed to read {path:?}"))?;
+
+        // Lines that fail to parse, e.g. one being appended, are skipped
+        for record in BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<UsageRecord>(&line).ok())
+            .filter(|record| since.is_none_or(|since| record.timestamp >= since))
+            .filter(|record| until.is_none_or(|until| record.timestamp < until))
+        {
+            report.total.add(&record.usage);
+            report
+                .by_alias
+                .entry(record.alias)
+                .or_default()
+                .add(&record.usage);
+            report
+                .by_upstream
+                .entry(record.upstream)
+                .or_default()
+                .add(&record.usage);
+
+            if let Some(client) = record.client {
+                report
+                    .by_client
+                    .entry(client)
+                    .or_default()
+                    .add(&record.usage);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Parses an RFC 3339 timestamp, a `YYYY-MM-DD` date (midnight UTC) or a duration ago like `30m`,
+/// `24h` or `7d`.
+pub fn parse_time(value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date
+            .and_hms_opt(0, 0, 0)
+            .expect("Midnight exists")
+            .and_utc());
+    }
+
+    let (amount, unit) = value.split_at(
+        value
+            .char_indices()
+            .last()
+            .map(|(index, _)| index)
+            .unwrap_or_default(),
+    );
+
+    let Ok(amount) = amount.parse::<i64>() else {
+        bail!(
+            "Expected an RFC 3339 timestamp, a YYYY-MM-DD date or a duration like 24h, found '{value}'"
+        );
+    };
+
+    let ago = match unit {
+        "s" => TimeDelta::try_seconds(amount),
+        "m" => TimeDelta::try_minutes(amount),
+        "h" => TimeDelta::try_hours(amount),
+        "d" => TimeDelta::try_days(amount),
+        _ => bail!("Unknown duration unit '{unit}' in '{value}', expected s, m, h or d"),
+    };
+
+    ago.and_then(|ago| now.checked_sub_signed(ago))
+        .with_context(|| format!("Duration '{value}' is out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-03-15T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn timestamps_are_parsed() {
+        assert_eq!(
+            parse_time("2026-03-14T08:30:00+02:00", now()).unwrap(),
+            DateTime::parse_from_rfc3339("2026-03-14T06:30:00Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn dates_are_midnight_utc() {
+        assert_eq!(
+            parse_time("2026-03-01", now()).unwrap(),
+            DateTime::parse_from_rfc3339("2026-03-01T00:00:00Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn durations_are_subtracted_from_now() {
+        assert_eq!(
+            parse_time("30s", now()).unwrap(),
+            now() - TimeDelta::seconds(30)
+        );
+        assert_eq!(
+            parse_time("30m", now()).unwrap(),
+            now() - TimeDelta::minutes(30)
+        );
+        assert_eq!(
+            parse_time("24h", now()).unwrap(),
+            now() - TimeDelta::hours(24)
+        );
+        assert_eq!(parse_time("7d", now()).unwrap(), now() - TimeDelta::days(7));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for value in ["", "d", "7w", "yesterday", "2026-13-01", "999999999999d"] {
+            assert!(parse_time(value, now()).is_err(), "{value}");
+        }
+    }
+}